@@ -0,0 +1,131 @@
+//! Benchmark comparing TCP forwarding with and without zero-copy.
+//!
+//! A client sends a fixed amount of data through a TCP session to a
+//! sink that discards it. The throughput and the CPU time used by the
+//! process are reported for each mode. The client and the sink run in
+//! the same process, so the CPU time includes their share as well,
+//! which is the same for both modes.
+//!
+//! Run with `cargo bench --bench splice`. The amount of data can be
+//! changed by setting `BENCH_MEGABYTES`.
+
+use router::{
+    protocol::{tcp::TcpSession, throttle::Throttle},
+    session::{
+        registry::Registry,
+        stats::RuleStats,
+        strategy::{StrategyFactory, Tables},
+        Mode, Protocol, Rule,
+    },
+};
+use std::{
+    env,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{self, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+    time,
+};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// CPU time used by the process, both user and system time.
+fn cpu_time() -> Duration {
+    // SAFETY: `getrusage` only writes to the provided struct.
+    let usage = unsafe {
+        let mut usage = std::mem::zeroed::<libc::rusage>();
+        libc::getrusage(libc::RUSAGE_SELF, &mut usage);
+        usage
+    };
+    let to_duration = |time: libc::timeval| {
+        Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+    };
+    to_duration(usage.ru_utime) + to_duration(usage.ru_stime)
+}
+
+/// Get an address with a free port.
+fn free_address() -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+async fn connect(addr: SocketAddr) -> TcpStream {
+    loop {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return stream,
+            Err(_) => time::sleep(Duration::from_millis(10)).await,
+        }
+    }
+}
+
+/// Forward `total` bytes through a session and return the wall-clock
+/// time and the CPU time used.
+async fn run(zero_copy: bool, total: usize) -> (Duration, Duration) {
+    let sink = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let destination = sink.local_addr().unwrap();
+    let receiver = tokio::spawn(async move {
+        let (mut stream, _) = sink.accept().await.unwrap();
+        io::copy(&mut stream, &mut io::sink()).await.unwrap()
+    });
+
+    let source = free_address();
+    let mut rule = Rule::new(
+        Protocol::Tcp,
+        Mode::RoundRobin,
+        source.into(),
+        vec![destination.into()],
+    );
+    rule.zero_copy = zero_copy;
+    let tables = Tables::default();
+    let strategy = StrategyFactory::make(&rule, &tables);
+    let session = TcpSession::new(
+        0,
+        rule,
+        strategy,
+        Arc::new(RuleStats::new()),
+        Arc::new(Throttle::default()),
+        Arc::new(Registry::new()),
+        tables,
+    )
+    .await;
+    let session = tokio::spawn(session.start());
+
+    let mut client = connect(source).await;
+    let chunk = vec![0xA5; CHUNK_SIZE];
+    let (start, cpu_start) = (Instant::now(), cpu_time());
+    let mut sent = 0;
+    while sent < total {
+        client.write_all(&chunk).await.unwrap();
+        sent += chunk.len();
+    }
+    client.shutdown().await.unwrap();
+    let received = receiver.await.unwrap();
+    let result = (start.elapsed(), cpu_time() - cpu_start);
+
+    assert_eq!(received as usize, sent);
+    session.abort();
+    result
+}
+
+fn main() {
+    let megabytes: usize = env::var("BENCH_MEGABYTES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(2048);
+    let runtime = Runtime::new().unwrap();
+    for (name, zero_copy) in &[("copy", false), ("splice", true)] {
+        let (elapsed, cpu) = runtime.block_on(run(*zero_copy, megabytes * 1024 * 1024));
+        println!(
+            "{:>6}: {} MiB in {:.2?}, {:.1} MiB/s, {:.2?} CPU",
+            name,
+            megabytes,
+            elapsed,
+            megabytes as f64 / elapsed.as_secs_f64(),
+            cpu,
+        );
+    }
+}
@@ -1,14 +1,21 @@
 //! Check the format of a configuration file.
 
-use std::{env::args, fs::read_to_string};
+use router::config::Config;
+use std::{env::args, process::exit};
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<_> = args().collect();
-    for filename in &args[1..] {
+fn main() {
+    let mut failed = false;
+    for filename in args().skip(1) {
         println!("{}:", filename);
-        let config_text = read_to_string(filename)?;
-        let json = serde_json::from_str(&config_text)?;
-        println!("{:#?}", json);
+        match Config::from_file(&filename) {
+            Ok(config) => println!("{:#?}", config),
+            Err(err) => {
+                eprintln!("{}: {}", filename, err);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        exit(1);
     }
-    Ok(())
 }
@@ -57,12 +57,12 @@ async fn main() {
     // Config string takes precedence, if given.
     let config = match matches.value_of("config_string") {
         Some(config_string) => {
-            Config::from_str(&config_string).expect("Unable to read config string")
+            Config::from_str(config_string).expect("Unable to read config string")
         }
         None => {
             let config_file = matches.value_of("config_file").unwrap_or("config.yaml");
             debug!("Reading from file '{}'", config_file);
-            Config::from_file(&config_file).expect("unable to read config file")
+            Config::from_file(config_file).expect("unable to read config file")
         }
     };
 
@@ -10,10 +10,10 @@
 //! following fields:
 //!
 //! - **port** is the port to listen on. If it is "*", then it means
-//! pick a random port to listen on.
+//!   pick a random port to listen on.
 //!
 //! - **address** is a full address to listen on. This can be used for
-//! machines that have several network interfaces.
+//!   machines that have several network interfaces.
 //!
 //! # Forwarding rules
 //!
@@ -21,8 +21,10 @@
 //!
 //! - **protocol** is the protocol that the section should use. It can be
 //!   either `Udp` or `Tcp` (it is case-sensitive).
-//! - **mode** can be either `Broadcast` or `RoundRobin` and the default
-//!   is `Broadcast` for UDP and `RoundRobin` for TCP.
+//! - **mode** can be either `Broadcast`, `RoundRobin`,
+//!   `WeightedRoundRobin`, `LeastConnections`, `ConsistentHash`,
+//!   `Random`, `P2c`, `PeakEwma`, or `Script` and the default is
+//!   `Broadcast` for UDP and `RoundRobin` for TCP.
 //!  
 //!   - In broadcast mode, each packet will be sent to all destinations,
 //!     which only make sense for UDP.
@@ -30,12 +32,139 @@
 //!   - In round-robin mode, each packet will be sent to or connection
 //!     established with one target at a time in a round-robin fashion.
 //!
+//!   - In least-connections mode, each connection is established with
+//!     the target with the fewest active connections, which only makes
+//!     sense for TCP.
+//!
+//!   - In random mode, each packet or connection is sent to a random
+//!     target, and in `p2c` mode to the less loaded of two random
+//!     targets. The optional **seed** makes the choices repeatable.
+//!
+//!   - In peak-EWMA mode, each connection or packet goes to the
+//!     target with the lowest smoothed connect or response time,
+//!     weighted by its connections in flight. The optional
+//!     **peak_ewma** field has the `decay_ms` time of the
+//!     average and the `penalty_ms` time assumed for new targets.
+//!
+//!   - In script mode, the targets are picked by a Rhai script. The
+//!     **script** field has the `path` of the script file and the
+//!     optional `max_operations` for each run of the script.
+//!
+//!   - In consistent-hash mode, each client is mapped to a target by
+//!     hashing its address. The optional **consistent_hash** field has
+//!     the `key`, either `"ip"` or `"address"`, and the number of
+//!     `virtual_nodes` for each target.
+//!
+//! - **sticky** is optional and sends each client to the same target
+//!   until it has been idle for `ttl_ms` milliseconds or the target
+//!   fails. It has the `key`, either `"ip"` or `"address"`, and the
+//!   `max_clients` to remember.
+//!
+//! - **source_routes** is an optional list of routes, each with a
+//!   client `network`, an optional client `port_range`, and the
+//!   `destinations` and optional `mode` for the matching clients. The
+//!   first matching route is used, and other clients use the rule
+//!   destinations.
+//!
+//! - **strategy** is optional and names a strategy registered by a
+//!   program using the router as a library, with `name` and optional
+//!   JSON `params`. It is used instead of the mode.
+//!
+//! - **weights** is an optional map from destinations to weights,
+//!   which is used in weighted round-robin mode. Destinations without
+//!   a weight have weight 1.
+//!
 //! - **source** is a source addresses that the router should
 //!   listen on.
 //!  
 //! - **destinations** is a list of destination addresses that the router
 //!   should send packets or establish connections with.
 //!
+//!   Addresses are either IP socket addresses or Unix domain sockets,
+//!   written as `unix:/path/to/socket` or `unix:@name` for the
+//!   abstract namespace.
+//!
+//! - **backups** is optional and gives `tiers` of destinations that
+//!   are only used when all destinations of the tiers before them are
+//!   down. Failed destinations are tried again after `retry_ms`
+//!   milliseconds, which is 10 seconds by default. It is only valid
+//!   for TCP rules.
+//!
+//! - **sni** is an optional map from hostname patterns to lists of
+//!   destination addresses. It is only valid for TCP rules and is used
+//!   to pick destinations based on the server name the client sent in
+//!   the TLS ClientHello.
+//!
+//! - **upstream_tls** is optional and makes TCP rules connect to the
+//!   destinations using TLS. It has the fields `ca_file` and
+//!   `server_name`, which are used to verify the destination, and the
+//!   optional fields `cert_file` and `key_file` with a client
+//!   certificate for destinations that require mutual TLS.
+//!
+//! - **tls** is optional and makes TCP rules terminate TLS from
+//!   clients. It has the fields `cert_file` and `key_file` with the
+//!   certificate presented to clients, and the optional field
+//!   `client_ca_file` to require client certificates signed by one of
+//!   the CA certificates in the file. With a client CA file,
+//!   `client_names` can list patterns that the client identity has to
+//!   match and `client_pools` can map client identity patterns to
+//!   destination pools.
+//!
+//! - **max_connections** and **max_connections_per_client_ip** are
+//!   optional limits on the number of active connections for TCP
+//!   rules, for the rule and for each client address respectively.
+//!   **over_limit** decides what happens with connections over the
+//!   limit: `"close"` closes them immediately, which is the default,
+//!   and `{"queue": {"timeout_ms": 500}}` makes them wait for a free
+//!   slot for at most the given time.
+//!
+//! - **idle_timeout_ms** and **max_lifetime_ms** are optional timeouts
+//!   for TCP rules. Connections are closed if no data was transferred
+//!   in either direction for the idle timeout, or if they have been
+//!   open for the maximum lifetime.
+//!
+//! - **rate_limit** is an optional bandwidth limit with the fields
+//!   `per_rule` and `per_connection`, each holding `bytes_per_second`
+//!   and `burst`. The connection limit is only valid for TCP rules.
+//!
+//! - **zero_copy** makes TCP rules forward data using `splice(2)` on
+//!   Linux. It cannot be combined with `tls` or `upstream_tls`.
+//!
+//! - **warm_pool** makes TCP rules keep connections open to the
+//!   destinations, with the fields `min_idle` for the number of idle
+//!   connections to each destination, `max_age_ms` for how long an
+//!   idle connection is kept, and `server_first_safe` to allow handing
+//!   out connections where the destination already sent data.
+//!
+//! - **socket_options** sets options on the sockets of the rule:
+//!   `nodelay`, `keepalive` (with `idle_ms`, `interval_ms` and
+//!   `count`), `recv_buffer_size`, `send_buffer_size`, `tos` or `dscp`,
+//!   and `v6only`. Keepalive and nodelay are only valid for TCP rules
+//!   and `v6only` only for IPv6 sources.
+//!
+//! - **sniff** makes TCP rules pick destinations based on the first
+//!   bytes sent by the client. It has a list of `routes`, each with a
+//!   `match` and `destinations`, and an optional `timeout_ms`. The
+//!   matchers are `"tls"`, `"http"`, `"ssh"`, `{"regex": "..."}` and
+//!   `"timeout"`. It cannot be combined with `tls` or `sni`.
+//!
+//! - **socket_file** sets the permissions of the socket file created
+//!   for a Unix domain socket source, with an octal `mode` and the
+//!   `owner` and `group` as names or numeric ids.
+//!
+//! - **shadow** makes TCP rules copy client data to a second
+//!   `destination` and discard its responses. The shadow connection
+//!   is dropped if more than `max_buffer` bytes (1 MiB by default) are
+//!   waiting to be sent to it. It cannot be combined with `zero_copy`.
+//!
+//! - **outbound_bind** sets the local `address` of connections and
+//!   packets sent to destinations, and optionally a `port_range` with
+//!   the `first` and `last` local port to use. The address has to be
+//!   of the same family as the IP destinations.
+//!
+//! Rules are validated when the configuration is read, so
+//! inconsistent rules are rejected with a configuration error.
+//!
 //! # Example
 //!
 //! Here is a simple configuration that will broadcast UDP traffic
@@ -70,7 +199,14 @@
 //!     ]
 //! }
 
-use crate::session::{strategy, Rule};
+use crate::{
+    protocol::{sni::HostMap, sniff, socket, tls},
+    session::{
+        rules::{OutboundBind, RateLimits, Sniff, SocketFile},
+        script::ScriptStrategy,
+        strategy, Endpoint, Mode, Protocol, Rule,
+    },
+};
 use serde::{Deserialize, Serialize};
 use std::{fs, net::SocketAddr};
 
@@ -100,9 +236,18 @@ pub enum Error {
 impl Config {
     pub fn from_json(json: &str) -> Result<Config> {
         let config: Config = serde_json::from_str(json)?;
+        config.validate()?;
         Ok(config)
     }
 
+    /// Check that all rules in the configuration are consistent.
+    pub fn validate(&self) -> Result<()> {
+        for rule in &self.rules {
+            rule.validate()?;
+        }
+        Ok(())
+    }
+
     pub fn to_json(&self) -> Result<String> {
         serde_json::to_string(self).map_err(|err| Error::JsonError(format!("JSON Error: {}", err)))
     }
@@ -165,12 +310,412 @@ impl std::convert::From<std::net::AddrParseError> for Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl RateLimits {
+    /// Check that the rate limits are usable.
+    pub fn validate(&self) -> Result<()> {
+        for limit in self.per_rule.iter().chain(&self.per_connection) {
+            if limit.bytes_per_second == 0 || limit.burst == 0 {
+                return Err(Error::ConfigError(
+                    "rate and burst of rate limits have to be positive".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Rule {
     pub fn from_json(data: &str) -> Result<Rule> {
         let rule: Rule = serde_json::from_str(data)?;
+        rule.validate()?;
         Ok(rule)
     }
 
+    /// Check that the rule is consistent.
+    pub fn validate(&self) -> Result<()> {
+        if !self.sni.is_empty() && self.protocol != Protocol::Tcp {
+            return Err(Error::ConfigError(
+                "server name routing is only supported for TCP".to_string(),
+            ));
+        }
+        for (pattern, destinations) in &self.sni {
+            HostMap::<()>::check_pattern(pattern).map_err(Error::ConfigError)?;
+            if destinations.is_empty() {
+                return Err(Error::ConfigError(format!(
+                    "no destinations for server name '{}'",
+                    pattern
+                )));
+            }
+        }
+        if let Some(named) = &self.strategy {
+            // Source routes without a mode of their own use the named
+            // strategy for their destinations as well.
+            let routes = self
+                .source_routes
+                .iter()
+                .filter(|route| route.mode.is_none());
+            let pools =
+                std::iter::once(&self.destinations).chain(routes.map(|route| &route.destinations));
+            for destinations in pools {
+                strategy::StrategyRegistry::build(named, destinations)
+                    .map_err(Error::ConfigError)?;
+            }
+        }
+        self.validate_source_routes()?;
+        // Source routes can use other modes than the rule, and the
+        // settings of a mode are only valid if some route uses it.
+        let modes: Vec<Mode> = std::iter::once(self.mode)
+            .chain(self.source_routes.iter().filter_map(|route| route.mode))
+            .collect();
+        if modes.contains(&Mode::LeastConnections) && self.protocol != Protocol::Tcp {
+            return Err(Error::ConfigError(
+                "least-connections mode is only supported for TCP".to_string(),
+            ));
+        }
+        if let Some(settings) = &self.peak_ewma {
+            if !modes.contains(&Mode::PeakEwma) {
+                return Err(Error::ConfigError(
+                    "peak-EWMA settings need peak-ewma mode".to_string(),
+                ));
+            }
+            if settings.decay_ms == Some(0) {
+                return Err(Error::ConfigError(
+                    "decay time has to be positive".to_string(),
+                ));
+            }
+        }
+        if let Some(settings) = &self.consistent_hash {
+            if !modes.contains(&Mode::ConsistentHash) {
+                return Err(Error::ConfigError(
+                    "consistent hash settings need consistent-hash mode".to_string(),
+                ));
+            }
+            if settings.virtual_nodes == Some(0) {
+                return Err(Error::ConfigError(
+                    "number of virtual nodes has to be positive".to_string(),
+                ));
+            }
+        }
+        match (&self.script, modes.contains(&Mode::Script)) {
+            (Some(settings), true) => {
+                if settings.max_operations == Some(0) {
+                    return Err(Error::ConfigError(
+                        "number of script operations has to be positive".to_string(),
+                    ));
+                }
+                if !settings.path.is_file() {
+                    return Err(Error::ConfigError(format!(
+                        "script '{}' is not a file",
+                        settings.path.display()
+                    )));
+                }
+                ScriptStrategy::new(&self.destinations, settings).map_err(Error::SyntaxError)?;
+            }
+            (None, true) => {
+                return Err(Error::ConfigError("script mode needs a script".to_string()))
+            }
+            (Some(_), false) => {
+                return Err(Error::ConfigError(
+                    "script settings need script mode".to_string(),
+                ))
+            }
+            (None, false) => {}
+        }
+        if let Some(settings) = &self.sticky {
+            if settings.ttl_ms == Some(0) {
+                return Err(Error::ConfigError(
+                    "sticky session time has to be positive".to_string(),
+                ));
+            }
+            if settings.max_clients == Some(0) {
+                return Err(Error::ConfigError(
+                    "number of sticky clients has to be positive".to_string(),
+                ));
+            }
+        }
+        if !self.weights.is_empty() {
+            let destinations = self.all_destinations();
+            if let Some(unknown) = self.weights.keys().find(|d| !destinations.contains(d)) {
+                return Err(Error::ConfigError(format!(
+                    "weight given for {}, which is not a destination",
+                    unknown
+                )));
+            }
+        }
+        if let Some(backups) = &self.backups {
+            // A packet that was sent does not show that the
+            // destination is up, so failed destinations would never
+            // be left for the backups.
+            if self.protocol != Protocol::Tcp {
+                return Err(Error::ConfigError(
+                    "backup tiers are only supported for TCP".to_string(),
+                ));
+            }
+            if backups.tiers.iter().any(Vec::is_empty) {
+                return Err(Error::ConfigError(
+                    "backup tiers cannot be empty".to_string(),
+                ));
+            }
+            if backups.retry_ms == Some(0) {
+                return Err(Error::ConfigError(
+                    "backup retry time has to be positive".to_string(),
+                ));
+            }
+        }
+        if let Some(tls) = &self.upstream_tls {
+            if self.protocol != Protocol::Tcp {
+                return Err(Error::ConfigError(
+                    "upstream TLS is only supported for TCP".to_string(),
+                ));
+            }
+            if tls.cert_file.is_some() != tls.key_file.is_some() {
+                return Err(Error::ConfigError(
+                    "upstream TLS needs both a certificate and a key file".to_string(),
+                ));
+            }
+            tls::Connector::new(tls).map_err(|err| Error::ConfigError(err.to_string()))?;
+        }
+        if let Some(tls) = &self.tls {
+            if self.protocol != Protocol::Tcp {
+                return Err(Error::ConfigError(
+                    "TLS termination is only supported for TCP".to_string(),
+                ));
+            }
+            if tls.client_ca_file.is_none()
+                && (!tls.client_names.is_empty() || !tls.client_pools.is_empty())
+            {
+                return Err(Error::ConfigError(
+                    "client names and client pools need a client CA file".to_string(),
+                ));
+            }
+            for (pattern, destinations) in &tls.client_pools {
+                HostMap::<()>::check_pattern(pattern).map_err(Error::ConfigError)?;
+                if destinations.is_empty() {
+                    return Err(Error::ConfigError(format!(
+                        "no destinations for client '{}'",
+                        pattern
+                    )));
+                }
+            }
+            tls::Acceptor::new(tls).map_err(|err| Error::ConfigError(err.to_string()))?;
+        }
+        let limits = [self.max_connections, self.max_connections_per_client_ip];
+        if limits.iter().any(Option::is_some) && self.protocol != Protocol::Tcp {
+            return Err(Error::ConfigError(
+                "connection limits are only supported for TCP".to_string(),
+            ));
+        }
+        if limits.contains(&Some(0)) {
+            return Err(Error::ConfigError(
+                "connection limits have to be positive".to_string(),
+            ));
+        }
+        let timeouts = [self.idle_timeout_ms, self.max_lifetime_ms];
+        if timeouts.iter().any(Option::is_some) && self.protocol != Protocol::Tcp {
+            return Err(Error::ConfigError(
+                "connection timeouts are only supported for TCP".to_string(),
+            ));
+        }
+        if timeouts.contains(&Some(0)) {
+            return Err(Error::ConfigError(
+                "connection timeouts have to be positive".to_string(),
+            ));
+        }
+        if let Some(limits) = &self.rate_limit {
+            if limits.per_connection.is_some() && self.protocol != Protocol::Tcp {
+                return Err(Error::ConfigError(
+                    "connection rate limits are only supported for TCP".to_string(),
+                ));
+            }
+            limits.validate()?;
+        }
+        if self.zero_copy && self.protocol != Protocol::Tcp {
+            return Err(Error::ConfigError(
+                "zero-copy forwarding is only supported for TCP".to_string(),
+            ));
+        }
+        if self.zero_copy && (self.tls.is_some() || self.upstream_tls.is_some()) {
+            return Err(Error::ConfigError(
+                "zero-copy forwarding cannot be combined with TLS".to_string(),
+            ));
+        }
+        if let Some(pool) = &self.warm_pool {
+            if self.protocol != Protocol::Tcp {
+                return Err(Error::ConfigError(
+                    "connection pools are only supported for TCP".to_string(),
+                ));
+            }
+            if pool.min_idle == 0 || pool.max_age_ms == 0 {
+                return Err(Error::ConfigError(
+                    "size and age of connection pools have to be positive".to_string(),
+                ));
+            }
+        }
+        if let Some(shadow) = &self.shadow {
+            if self.protocol != Protocol::Tcp {
+                return Err(Error::ConfigError(
+                    "shadow traffic is only supported for TCP".to_string(),
+                ));
+            }
+            if self.zero_copy {
+                return Err(Error::ConfigError(
+                    "shadow traffic cannot be combined with zero-copy forwarding".to_string(),
+                ));
+            }
+            if shadow.max_buffer == Some(0) {
+                return Err(Error::ConfigError(
+                    "shadow buffer size has to be positive".to_string(),
+                ));
+            }
+        }
+        if let Some(sniff) = &self.sniff {
+            self.validate_sniff(sniff)?;
+        }
+        if let Some(file) = &self.socket_file {
+            self.validate_socket_file(file)?;
+        }
+        if let Some(bind) = &self.outbound_bind {
+            self.validate_outbound_bind(bind)?;
+        }
+        self.validate_socket_options()
+    }
+
+    fn validate_outbound_bind(&self, bind: &OutboundBind) -> Result<()> {
+        if let Some(range) = &bind.port_range {
+            if range.first == 0 || range.first > range.last {
+                return Err(Error::ConfigError(format!(
+                    "bad outbound port range {}-{}",
+                    range.first, range.last
+                )));
+            }
+        }
+        let shadow = self.shadow.iter().map(|shadow| shadow.destination.clone());
+        for destination in self.all_destinations().into_iter().chain(shadow) {
+            if destination
+                .ip()
+                .is_some_and(|ip| ip.is_ipv6() != bind.address.is_ipv6())
+            {
+                return Err(Error::ConfigError(format!(
+                    "destination {} is not of the same family as outbound address {}",
+                    destination, bind.address
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_socket_file(&self, file: &SocketFile) -> Result<()> {
+        if !matches!(self.source, Endpoint::Unix(_)) {
+            return Err(Error::ConfigError(
+                "socket file settings need a Unix domain socket path as source".to_string(),
+            ));
+        }
+        file.parse_mode().map_err(Error::ConfigError)?;
+        #[cfg(unix)]
+        {
+            let lookup = |err: std::io::Error| Error::ConfigError(err.to_string());
+            if let Some(owner) = &file.owner {
+                socket::user_id(owner).map_err(lookup)?;
+            }
+            if let Some(group) = &file.group {
+                socket::group_id(group).map_err(lookup)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_source_routes(&self) -> Result<()> {
+        for route in &self.source_routes {
+            if route.destinations.is_empty() {
+                return Err(Error::ConfigError(format!(
+                    "no destinations for source route {}",
+                    route.network
+                )));
+            }
+            if let Some(range) = &route.port_range {
+                if range.first > range.last {
+                    return Err(Error::ConfigError(format!(
+                        "bad source port range {}-{}",
+                        range.first, range.last
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_sniff(&self, sniff: &Sniff) -> Result<()> {
+        if self.protocol != Protocol::Tcp {
+            return Err(Error::ConfigError(
+                "protocol sniffing is only supported for TCP".to_string(),
+            ));
+        }
+        if self.tls.is_some() || !self.sni.is_empty() {
+            return Err(Error::ConfigError(
+                "protocol sniffing cannot be combined with tls or sni".to_string(),
+            ));
+        }
+        if sniff.timeout_ms == Some(0) {
+            return Err(Error::ConfigError(
+                "sniffing timeout has to be positive".to_string(),
+            ));
+        }
+        for route in &sniff.routes {
+            sniff::Matcher::new(&route.matcher)
+                .map_err(|err| Error::ConfigError(format!("bad sniffing pattern: {}", err)))?;
+            if route.destinations.is_empty() {
+                return Err(Error::ConfigError(format!(
+                    "no destinations for sniffing route {:?}",
+                    route.matcher
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_socket_options(&self) -> Result<()> {
+        let options = &self.socket_options;
+        let tcp_only = options.nodelay.is_some() || options.keepalive.is_some();
+        if tcp_only && self.protocol != Protocol::Tcp {
+            return Err(Error::ConfigError(
+                "nodelay and keepalive are only supported for TCP".to_string(),
+            ));
+        }
+        if let Some(keepalive) = &options.keepalive {
+            if keepalive.idle_ms < 1000
+                || keepalive
+                    .interval_ms
+                    .is_some_and(|interval| interval < 1000)
+                || keepalive.count == Some(0)
+            {
+                return Err(Error::ConfigError(
+                    "keepalive times have to be at least one second and count positive".to_string(),
+                ));
+            }
+        }
+        if options.recv_buffer_size == Some(0) || options.send_buffer_size == Some(0) {
+            return Err(Error::ConfigError(
+                "socket buffer sizes have to be positive".to_string(),
+            ));
+        }
+        if options.tos.is_some() && options.dscp.is_some() {
+            return Err(Error::ConfigError(
+                "only one of tos and dscp can be given".to_string(),
+            ));
+        }
+        if options.dscp.is_some_and(|dscp| dscp > 63) {
+            return Err(Error::ConfigError(
+                "dscp has to be between 0 and 63".to_string(),
+            ));
+        }
+        if options.v6only.is_some() && !self.source.is_ipv6() {
+            return Err(Error::ConfigError(
+                "v6only is only supported for IPv6 sources".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
     pub fn to_json(&self) -> Result<String> {
         serde_json::to_string(self).map_err(|err| Error::JsonError(format!("JSON Error: {}", err)))
     }
@@ -199,8 +744,7 @@ impl Config {
     pub fn from_file(filename: &str) -> Result<Config> {
         info!("Loading configuration using path '{}'", filename);
         let contents = fs::read_to_string(filename)?;
-        let config = serde_json::from_str(&contents)?;
-        Ok(config)
+        Self::from_json(&contents)
     }
 }
 
@@ -213,14 +757,20 @@ impl Default for Config {
 impl std::str::FromStr for Rule {
     type Err = Error;
     fn from_str(text: &str) -> Result<Self> {
-        serde_json::from_str(text).map_err(|err| Error::JsonError(format!("{}", err)))
+        let rule: Rule =
+            serde_json::from_str(text).map_err(|err| Error::JsonError(format!("{}", err)))?;
+        rule.validate()?;
+        Ok(rule)
     }
 }
 
 impl std::str::FromStr for Config {
     type Err = Error;
     fn from_str(text: &str) -> Result<Self> {
-        serde_json::from_str(text).map_err(|err| Error::JsonError(format!("{}", err)))
+        let config: Config =
+            serde_json::from_str(text).map_err(|err| Error::JsonError(format!("{}", err)))?;
+        config.validate()?;
+        Ok(config)
     }
 }
 
@@ -264,12 +814,12 @@ mod tests {
                 .parse();
         assert_eq!(
             rule,
-            Ok(Rule {
-                protocol: Protocol::Udp,
-                mode: Mode::Broadcast,
-                source: "127.0.0.1:8080".parse().unwrap(),
-                destinations: vec![]
-            })
+            Ok(Rule::new(
+                Protocol::Udp,
+                Mode::Broadcast,
+                "127.0.0.1:8080".parse().unwrap(),
+                vec![]
+            ))
         );
 
         let rule: Result<Rule> = r#"{"protocol":"udp",
@@ -278,12 +828,12 @@ mod tests {
             .parse();
         assert_eq!(
             rule,
-            Ok(Rule {
-                protocol: Protocol::Udp,
-                mode: Mode::Broadcast,
-                source: "127.0.0.1:9080".parse().unwrap(),
-                destinations: vec![]
-            })
+            Ok(Rule::new(
+                Protocol::Udp,
+                Mode::Broadcast,
+                "127.0.0.1:9080".parse().unwrap(),
+                vec![]
+            ))
         );
 
         let rule: Result<Rule> = r#"{"protocol":"udp",
@@ -292,15 +842,15 @@ mod tests {
             .parse();
         assert_eq!(
             rule,
-            Ok(Rule {
-                protocol: Protocol::Udp,
-                mode: Mode::Broadcast,
-                source: "127.0.0.1:9080".parse().unwrap(),
-                destinations: vec![
+            Ok(Rule::new(
+                Protocol::Udp,
+                Mode::Broadcast,
+                "127.0.0.1:9080".parse().unwrap(),
+                vec![
                     "127.0.0.1:9081".parse().unwrap(),
                     "127.0.0.1:9082".parse().unwrap()
                 ]
-            })
+            ))
         );
 
         let rule: Result<Rule> = r#"{"protocol":"udp",
@@ -309,12 +859,12 @@ mod tests {
             .parse();
         assert_eq!(
             rule,
-            Ok(Rule {
-                protocol: Protocol::Udp,
-                mode: Mode::Broadcast,
-                source: "127.0.0.1:9080".parse().unwrap(),
-                destinations: vec!["127.0.0.1:9081".parse().unwrap()]
-            })
+            Ok(Rule::new(
+                Protocol::Udp,
+                Mode::Broadcast,
+                "127.0.0.1:9080".parse().unwrap(),
+                vec!["127.0.0.1:9081".parse().unwrap()]
+            ))
         );
     }
 
@@ -338,15 +888,15 @@ mod tests {
             config,
             Ok(Config {
                 web: Some(Web::Port(Some(1111))),
-                rules: vec![Rule {
-                    protocol: Protocol::Udp,
-                    mode: Mode::Broadcast,
-                    source: "127.0.0.1:9080".parse().unwrap(),
-                    destinations: vec![
+                rules: vec![Rule::new(
+                    Protocol::Udp,
+                    Mode::Broadcast,
+                    "127.0.0.1:9080".parse().unwrap(),
+                    vec![
                         "127.0.0.1:9081".parse().unwrap(),
                         "127.0.0.1:9082".parse().unwrap()
                     ]
-                }]
+                )]
             })
         );
     }
@@ -369,15 +919,15 @@ mod tests {
             config,
             Ok(Config {
                 web: None,
-                rules: vec![Rule {
-                    protocol: Protocol::Udp,
-                    mode: Mode::Broadcast,
-                    source: "127.0.0.1:9080".parse().unwrap(),
-                    destinations: vec![
+                rules: vec![Rule::new(
+                    Protocol::Udp,
+                    Mode::Broadcast,
+                    "127.0.0.1:9080".parse().unwrap(),
+                    vec![
                         "127.0.0.1:9081".parse().unwrap(),
                         "127.0.0.1:9082".parse().unwrap()
                     ]
-                }]
+                )]
             })
         );
     }
@@ -386,20 +936,278 @@ mod tests {
     fn test_config_serialize_no_web() {
         let config = Config {
             web: None,
-            rules: vec![Rule {
-                protocol: Protocol::Udp,
-                mode: Mode::Broadcast,
-                source: "127.0.0.1:9080".parse().unwrap(),
-                destinations: vec![
+            rules: vec![Rule::new(
+                Protocol::Udp,
+                Mode::Broadcast,
+                "127.0.0.1:9080".parse().unwrap(),
+                vec![
                     "127.0.0.1:9081".parse().unwrap(),
                     "127.0.0.1:9082".parse().unwrap(),
                 ],
-            }],
+            )],
         };
         let result = r#"{"rules":[{"protocol":"udp","mode":"broadcast","source":"127.0.0.1:9080","destinations":["127.0.0.1:9081","127.0.0.1:9082"]}]}"#;
         assert_eq!(serde_json::to_string(&config).unwrap(), result.to_string());
     }
 
+    #[test]
+    fn test_rule_sni() {
+        let rule: Result<Rule> = r#"{"protocol": "tcp", "mode": "round-robin",
+                "source": "127.0.0.1:9443", "destinations": ["127.0.0.1:9444"],
+                "sni": {"*.example.com": ["127.0.0.1:9445"]}}"#
+            .parse();
+        let mut expected = Rule::new(
+            Protocol::Tcp,
+            Mode::RoundRobin,
+            "127.0.0.1:9443".parse().unwrap(),
+            vec!["127.0.0.1:9444".parse().unwrap()],
+        );
+        expected.sni.insert(
+            "*.example.com".to_string(),
+            vec!["127.0.0.1:9445".parse().unwrap()],
+        );
+        assert_eq!(rule, Ok(expected));
+
+        let rule: Result<Rule> = r#"{"protocol": "udp", "mode": "broadcast",
+                "source": "127.0.0.1:9443", "destinations": ["127.0.0.1:9444"],
+                "sni": {"example.com": ["127.0.0.1:9445"]}}"#
+            .parse();
+        assert!(matches!(rule, Err(Error::ConfigError(_))));
+
+        let rule: Result<Rule> = r#"{"protocol": "tcp", "mode": "round-robin",
+                "source": "127.0.0.1:9443", "destinations": ["127.0.0.1:9444"],
+                "sni": {"www.*.com": ["127.0.0.1:9445"]}}"#
+            .parse();
+        assert!(matches!(rule, Err(Error::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_rule_upstream_tls() {
+        let rule: Result<Rule> = r#"{"protocol": "tcp", "mode": "round-robin",
+                "source": "127.0.0.1:9443", "destinations": ["127.0.0.1:9444"],
+                "upstream_tls": {"ca_file": "ca.pem", "server_name": "example.com",
+                                 "cert_file": "client.pem"}}"#
+            .parse();
+        assert!(matches!(rule, Err(Error::ConfigError(_))));
+
+        let rule: Result<Rule> = r#"{"protocol": "tcp", "mode": "round-robin",
+                "source": "127.0.0.1:9443", "destinations": ["127.0.0.1:9444"],
+                "upstream_tls": {"ca_file": "/does/not/exist.pem",
+                                 "server_name": "example.com"}}"#
+            .parse();
+        assert!(matches!(rule, Err(Error::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_rule_backups() {
+        let rule: Result<Rule> = r#"{"protocol": "tcp", "mode": "round-robin",
+                "source": "127.0.0.1:9080", "destinations": ["127.0.0.1:9081"],
+                "backups": {"tiers": [["127.0.0.1:9082"]], "retry_ms": 5000}}"#
+            .parse();
+        assert!(rule.is_ok());
+
+        let rule: Result<Rule> = r#"{"protocol": "udp", "mode": "round-robin",
+                "source": "127.0.0.1:9080", "destinations": ["127.0.0.1:9081"],
+                "backups": {"tiers": [["127.0.0.1:9082"]]}}"#
+            .parse();
+        assert!(matches!(rule, Err(Error::ConfigError(_))));
+
+        let rule: Result<Rule> = r#"{"protocol": "tcp", "mode": "round-robin",
+                "source": "127.0.0.1:9080", "destinations": ["127.0.0.1:9081"],
+                "backups": {"tiers": [[]]}}"#
+            .parse();
+        assert!(matches!(rule, Err(Error::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_rule_rate_limit() {
+        let rule: Result<Rule> = r#"{"protocol": "udp", "mode": "broadcast",
+                "source": "127.0.0.1:9080", "destinations": ["127.0.0.1:9081"],
+                "rate_limit": {"per_rule": {"bytes_per_second": 1000, "burst": 1500}}}"#
+            .parse();
+        assert!(rule.is_ok());
+
+        let rule: Result<Rule> = r#"{"protocol": "udp", "mode": "broadcast",
+                "source": "127.0.0.1:9080", "destinations": ["127.0.0.1:9081"],
+                "rate_limit": {"per_connection": {"bytes_per_second": 1000, "burst": 1500}}}"#
+            .parse();
+        assert!(matches!(rule, Err(Error::ConfigError(_))));
+
+        let rule: Result<Rule> = r#"{"protocol": "tcp", "mode": "round-robin",
+                "source": "127.0.0.1:9090", "destinations": ["127.0.0.1:9091"],
+                "rate_limit": {"per_connection": {"bytes_per_second": 0, "burst": 1500}}}"#
+            .parse();
+        assert!(matches!(rule, Err(Error::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_rule_socket_options() {
+        let rule: Result<Rule> = r#"{"protocol": "tcp", "mode": "round-robin",
+                "source": "127.0.0.1:9090", "destinations": ["127.0.0.1:9091"],
+                "socket_options": {"nodelay": true, "dscp": 46,
+                                   "keepalive": {"idle_ms": 60000, "count": 4}}}"#
+            .parse();
+        assert!(rule.is_ok());
+
+        let invalid = [
+            ("udp", "127.0.0.1:9080", r#"{"nodelay": true}"#),
+            ("tcp", "127.0.0.1:9080", r#"{"tos": 16, "dscp": 10}"#),
+            ("tcp", "127.0.0.1:9080", r#"{"dscp": 64}"#),
+            ("tcp", "127.0.0.1:9080", r#"{"v6only": true}"#),
+        ];
+        for (protocol, source, options) in &invalid {
+            let json = format!(
+                r#"{{"protocol": "{}", "mode": "round-robin", "source": "{}",
+                     "destinations": [], "socket_options": {}}}"#,
+                protocol, source, options
+            );
+            let rule: Result<Rule> = json.parse();
+            assert!(matches!(rule, Err(Error::ConfigError(_))), "{}", json);
+        }
+    }
+
+    #[test]
+    fn test_rule_sniff() {
+        let rule: Result<Rule> = r#"{"protocol": "tcp", "mode": "round-robin",
+                "source": "127.0.0.1:9090", "destinations": ["127.0.0.1:9091"],
+                "sniff": {"timeout_ms": 200, "routes": [
+                    {"match": "tls", "destinations": ["127.0.0.1:9092"]},
+                    {"match": {"regex": "^\\x00"}, "destinations": ["127.0.0.1:9093"]},
+                    {"match": "timeout", "destinations": ["127.0.0.1:9094"]}]}}"#
+            .parse();
+        assert!(rule.is_ok(), "{:?}", rule);
+
+        let rule: Result<Rule> = r#"{"protocol": "tcp", "mode": "round-robin",
+                "source": "127.0.0.1:9090", "destinations": ["127.0.0.1:9091"],
+                "sniff": {"routes": [
+                    {"match": {"regex": "(unclosed"}, "destinations": ["127.0.0.1:9092"]}]}}"#
+            .parse();
+        assert!(matches!(rule, Err(Error::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_rule_outbound_bind() {
+        let rule: Result<Rule> = r#"{"protocol": "udp", "mode": "broadcast",
+                "source": "127.0.0.1:9090", "destinations": ["127.0.0.1:9091"],
+                "outbound_bind": {"address": "127.0.0.1",
+                                  "port_range": {"first": 40000, "last": 40999}}}"#
+            .parse();
+        assert!(rule.is_ok(), "{:?}", rule);
+
+        let rule: Result<Rule> = r#"{"protocol": "tcp", "mode": "round-robin",
+                "source": "127.0.0.1:9090", "destinations": ["[::1]:9091"],
+                "outbound_bind": {"address": "127.0.0.1"}}"#
+            .parse();
+        assert!(matches!(rule, Err(Error::ConfigError(_))));
+
+        let rule: Result<Rule> = r#"{"protocol": "tcp", "mode": "round-robin",
+                "source": "127.0.0.1:9090", "destinations": ["127.0.0.1:9091"],
+                "outbound_bind": {"address": "127.0.0.1",
+                                  "port_range": {"first": 41000, "last": 40000}}}"#
+            .parse();
+        assert!(matches!(rule, Err(Error::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_rule_strategy() {
+        strategy::StrategyRegistry::register("config-test", |destinations, params| {
+            match params.get("ok") {
+                Some(serde_json::Value::Bool(true)) => {
+                    Ok(Box::new(strategy::RoundRobinStrategy::new(destinations)))
+                }
+                _ => Err("not ok".to_string()),
+            }
+        });
+        let rule = |strategy: &str| -> Result<Rule> {
+            format!(
+                r#"{{"protocol": "tcp", "mode": "round-robin",
+                     "source": "127.0.0.1:9090", "destinations": ["127.0.0.1:9091"],
+                     "strategy": {}}}"#,
+                strategy
+            )
+            .parse()
+        };
+        let result = rule(r#"{"name": "config-test", "params": {"ok": true}}"#);
+        assert!(result.is_ok(), "{:?}", result);
+        let result = rule(r#"{"name": "config-test", "params": {"ok": false}}"#);
+        assert!(matches!(result, Err(Error::ConfigError(_))));
+        let result = rule(r#"{"name": "config-missing"}"#);
+        assert!(matches!(result, Err(Error::ConfigError(_))));
+
+        // The strategy is also used by source routes without a mode.
+        strategy::StrategyRegistry::register("config-single", |destinations, _| match destinations
+            .len()
+        {
+            1 => Ok(Box::new(strategy::RoundRobinStrategy::new(destinations))),
+            _ => Err("need one destination".to_string()),
+        });
+        let rule = |mode: &str| -> Result<Rule> {
+            format!(
+                r#"{{"protocol": "tcp", "mode": "round-robin",
+                     "source": "127.0.0.1:9090", "destinations": ["127.0.0.1:9091"],
+                     "strategy": {{"name": "config-single"}},
+                     "source_routes": [{{"network": "10.0.0.0/8", {}
+                         "destinations": ["127.0.0.1:9092", "127.0.0.1:9093"]}}]}}"#,
+                mode
+            )
+            .parse()
+        };
+        let result = rule("");
+        assert!(matches!(result, Err(Error::ConfigError(_))));
+        let result = rule(r#""mode": "round-robin","#);
+        assert!(result.is_ok(), "{:?}", result);
+    }
+
+    #[test]
+    fn test_rule_script() {
+        let path = std::env::temp_dir().join(format!("router-config-{}.rhai", std::process::id()));
+        let rule = || -> Result<Rule> {
+            format!(
+                r#"{{"protocol": "udp", "mode": "script",
+                     "source": "127.0.0.1:9090", "destinations": ["127.0.0.1:9091"],
+                     "script": {{"path": "{}"}}}}"#,
+                path.display()
+            )
+            .parse()
+        };
+        fs::write(&path, "destinations[0]").unwrap();
+        let result = rule();
+        assert!(result.is_ok(), "{:?}", result);
+        fs::write(&path, "destinations[").unwrap();
+        let result = rule();
+        assert!(matches!(result, Err(Error::SyntaxError(_))));
+        fs::remove_file(&path).unwrap();
+        let result = rule();
+        assert!(matches!(result, Err(Error::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_rule_source_routes() {
+        let rule = |routes: &str| -> Result<Rule> {
+            format!(
+                r#"{{"protocol": "udp", "mode": "round-robin",
+                     "source": "127.0.0.1:9090", "destinations": ["127.0.0.1:9091"],
+                     "source_routes": {}}}"#,
+                routes
+            )
+            .parse()
+        };
+        let result = rule(
+            r#"[{"network": "10.0.0.0/8", "port_range": {"first": 1000, "last": 1999},
+                 "destinations": ["127.0.0.1:9092"], "mode": "broadcast"}]"#,
+        );
+        assert!(result.is_ok(), "{:?}", result);
+        let result = rule(r#"[{"network": "10.0.0.0/33", "destinations": ["127.0.0.1:9092"]}]"#);
+        assert!(matches!(result, Err(Error::JsonError(_))));
+        let result = rule(r#"[{"network": "10.0.0.0/8", "destinations": []}]"#);
+        assert!(matches!(result, Err(Error::ConfigError(_))));
+        let result = rule(
+            r#"[{"network": "10.0.0.0/8", "destinations": ["127.0.0.1:9092"],
+                 "mode": "least-connections"}]"#,
+        );
+        assert!(matches!(result, Err(Error::ConfigError(_))));
+    }
+
     #[test]
     fn test_web_parse() {
         assert_eq!(
@@ -0,0 +1,156 @@
+//! Connection limits.
+//!
+//! Each accepted connection holds a permit for as long as it is
+//! active. Permits are only handed out if the number of active
+//! connections for the rule, and for the client address, is below
+//! the limits configured for the rule. Connections over the limit are
+//! either closed immediately or queued until a permit is available or
+//! the queue timeout expires.
+
+use crate::session::{rules::OverLimit, stats::RuleStats, Rule};
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::time;
+
+/// Limits on the number of connections for a rule.
+pub struct Limiter {
+    max_connections: Option<usize>,
+    max_per_client: Option<usize>,
+    over_limit: OverLimit,
+    stats: Arc<RuleStats>,
+}
+
+/// Permit for an active connection. The connection is removed from
+/// the counters when the permit is dropped.
+pub struct Permit {
+    client: Option<IpAddr>,
+    stats: Arc<RuleStats>,
+}
+
+impl Limiter {
+    pub fn new(rule: &Rule, stats: Arc<RuleStats>) -> Limiter {
+        Limiter {
+            max_connections: rule.max_connections,
+            max_per_client: rule.max_connections_per_client_ip,
+            over_limit: rule.over_limit,
+            stats,
+        }
+    }
+
+    /// Get a permit for a new connection from a client. Clients
+    /// without an IP address only count against the total limit.
+    ///
+    /// If the connection is rejected, the reason is returned and
+    /// counted in the rule statistics.
+    pub async fn acquire(&self, client: Option<IpAddr>) -> Result<Permit, &'static str> {
+        let deadline = match self.over_limit {
+            OverLimit::Close => None,
+            OverLimit::Queue { timeout_ms } => {
+                Some(Instant::now() + Duration::from_millis(timeout_ms))
+            }
+        };
+        loop {
+            // Register for notifications before checking the limits
+            // so that a connection closed in between is not missed.
+            let closed = self.stats.closed().notified();
+            let reason = match self.try_acquire(client) {
+                Ok(permit) => return Ok(permit),
+                Err(reason) => reason,
+            };
+            let remaining = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => {
+                    self.stats.reject_connection(reason);
+                    return Err(reason);
+                }
+            };
+            if time::timeout(remaining, closed).await.is_err() {
+                self.stats.reject_connection("queue-timeout");
+                return Err("queue-timeout");
+            }
+        }
+    }
+
+    fn try_acquire(&self, client: Option<IpAddr>) -> Result<Permit, &'static str> {
+        self.stats
+            .try_open(client, self.max_connections, self.max_per_client)?;
+        Ok(Permit {
+            client,
+            stats: self.stats.clone(),
+        })
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.stats.close(self.client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{Mode, Protocol};
+
+    fn limiter(total: usize, per_client: usize, over_limit: OverLimit) -> Limiter {
+        let mut rule = Rule::new(
+            Protocol::Tcp,
+            Mode::RoundRobin,
+            "127.0.0.1:9090".parse().unwrap(),
+            vec!["127.0.0.1:9091".parse().unwrap()],
+        );
+        rule.max_connections = Some(total);
+        rule.max_connections_per_client_ip = Some(per_client);
+        rule.over_limit = over_limit;
+        Limiter::new(&rule, Arc::new(RuleStats::new()))
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let limiter = limiter(3, 2, OverLimit::Close);
+        let first: IpAddr = "10.0.0.1".parse().unwrap();
+        let second: IpAddr = "10.0.0.2".parse().unwrap();
+        let _p1 = limiter.acquire(Some(first)).await.unwrap();
+        let p2 = limiter.acquire(Some(first)).await.unwrap();
+        assert_eq!(
+            limiter.acquire(Some(first)).await.err(),
+            Some("max-connections-per-client-ip")
+        );
+        let _p3 = limiter.acquire(Some(second)).await.unwrap();
+        assert_eq!(
+            limiter.acquire(Some(second)).await.err(),
+            Some("max-connections")
+        );
+        drop(p2);
+        let _p4 = limiter.acquire(Some(second)).await.unwrap();
+
+        let report = limiter.stats.report();
+        assert_eq!(report.active_connections, 3);
+        assert_eq!(report.active_clients.get(&first), Some(&1));
+        assert_eq!(report.active_clients.get(&second), Some(&2));
+        assert_eq!(report.rejected_connections.len(), 2);
+        assert!(serde_json::to_string(&report).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_queue() {
+        let limiter = Arc::new(limiter(1, 1, OverLimit::Queue { timeout_ms: 50 }));
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let permit = limiter.acquire(Some(client)).await.unwrap();
+        assert_eq!(
+            limiter.acquire(Some(client)).await.err(),
+            Some("queue-timeout")
+        );
+
+        let waiter = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire(Some(client)).await.map(|_| ()) }
+        });
+        time::sleep(Duration::from_millis(10)).await;
+        drop(permit);
+        assert_eq!(waiter.await.unwrap(), Ok(()));
+    }
+}
@@ -12,16 +12,39 @@
 // implied.  See the License for the specific language governing
 // permissions and limitations under the License.
 
+pub mod limit;
+pub mod pool;
+pub mod shadow;
+pub mod sni;
+pub mod sniff;
+pub mod socket;
+#[cfg(target_os = "linux")]
+pub mod splice;
+pub mod stream;
 pub mod tcp;
+pub mod throttle;
+pub mod tls;
 pub mod udp;
 
 #[derive(Debug)]
 pub enum Error {
     IoError,
+    TlsError(String),
+    SniffError(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IoError => write!(f, "I/O error"),
+            Error::TlsError(ref txt) => write!(f, "TLS error: {}", txt),
+            Error::SniffError(ref txt) => write!(f, "sniffing error: {}", txt),
+        }
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(_err: std::io::Error) -> Error {
         Error::IoError
@@ -0,0 +1,219 @@
+//! Pools of warm connections to destinations.
+//!
+//! A pool keeps a number of idle connections open to each destination
+//! of a rule so that new clients can be forwarded without waiting for
+//! a connection to be established. Connections are replaced in the
+//! background when they are handed out or reach the maximum age.
+//!
+//! A connection that the destination has already sent data on, or
+//! that the destination has closed, is discarded instead of handed
+//! out, unless the pool is configured to allow servers to speak first.
+
+use crate::{
+    protocol::socket,
+    session::rules::{OutboundBind, SocketOptions, WarmPool},
+};
+use futures::FutureExt;
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpStream, sync::Notify, time};
+
+/// Time between checks for expired connections.
+const REPLENISH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Idle connection in a pool.
+struct Idle {
+    stream: TcpStream,
+    created: Instant,
+}
+
+/// Pool of connections to a set of destinations.
+pub struct ConnectionPool {
+    min_idle: usize,
+    max_age: Duration,
+    server_first_safe: bool,
+    idle: Mutex<HashMap<SocketAddr, VecDeque<Idle>>>,
+    taken: Arc<Notify>,
+}
+
+impl ConnectionPool {
+    /// Create a pool for the destinations and start filling it in the
+    /// background using the socket options and outbound bind address.
+    /// The background task stops when the pool is dropped.
+    pub fn start<I>(
+        settings: &WarmPool,
+        options: &SocketOptions,
+        bind: Option<&OutboundBind>,
+        destinations: I,
+    ) -> Arc<ConnectionPool>
+    where
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        let destinations: BTreeSet<SocketAddr> = destinations.into_iter().collect();
+        let pool = Arc::new(ConnectionPool {
+            min_idle: settings.min_idle,
+            max_age: Duration::from_millis(settings.max_age_ms),
+            server_first_safe: settings.server_first_safe,
+            idle: Mutex::new(
+                destinations
+                    .iter()
+                    .map(|addr| (*addr, VecDeque::new()))
+                    .collect(),
+            ),
+            taken: Arc::new(Notify::new()),
+        });
+        let taken = pool.taken.clone();
+        tokio::spawn(replenish(
+            Arc::downgrade(&pool),
+            destinations,
+            *options,
+            bind.copied(),
+            taken,
+        ));
+        pool
+    }
+
+    /// Take an idle connection to a destination, if there is one that
+    /// can be used.
+    pub fn take(&self, addr: SocketAddr) -> Option<TcpStream> {
+        let stream = {
+            let mut idle = self.idle.lock().unwrap();
+            let connections = idle.get_mut(&addr)?;
+            let mut found = None;
+            while let Some(connection) = connections.pop_front() {
+                if self.is_usable(&connection) {
+                    found = Some(connection.stream);
+                    break;
+                }
+                debug!("discarding pooled connection to {}", addr);
+            }
+            found
+        };
+        self.taken.notify_one();
+        stream
+    }
+
+    /// Check if an idle connection can be handed out.
+    fn is_usable(&self, connection: &Idle) -> bool {
+        if connection.created.elapsed() >= self.max_age {
+            return false;
+        }
+        let mut buf = [0; 1];
+        match connection.stream.peek(&mut buf).now_or_never() {
+            // Nothing received, so the connection is untouched.
+            None => true,
+            // The destination has closed the connection or failed.
+            Some(Ok(0)) | Some(Err(_)) => false,
+            // The destination has sent data.
+            Some(Ok(_)) => self.server_first_safe,
+        }
+    }
+
+    /// Remove expired connections and return the number of missing
+    /// connections for each destination.
+    fn prune(&self) -> Vec<(SocketAddr, usize)> {
+        let mut idle = self.idle.lock().unwrap();
+        idle.iter_mut()
+            .map(|(addr, connections)| {
+                let max_age = self.max_age;
+                connections.retain(|connection| connection.created.elapsed() < max_age);
+                (*addr, self.min_idle.saturating_sub(connections.len()))
+            })
+            .collect()
+    }
+
+    fn add(&self, addr: SocketAddr, stream: TcpStream) {
+        let mut idle = self.idle.lock().unwrap();
+        if let Some(connections) = idle.get_mut(&addr) {
+            connections.push_back(Idle {
+                stream,
+                created: Instant::now(),
+            });
+        }
+    }
+}
+
+/// Keep the pool filled until it is dropped.
+async fn replenish(
+    pool: Weak<ConnectionPool>,
+    destinations: BTreeSet<SocketAddr>,
+    options: SocketOptions,
+    bind: Option<OutboundBind>,
+    taken: Arc<Notify>,
+) {
+    debug!("filling connection pool for {:?}", destinations);
+    loop {
+        let missing = match pool.upgrade() {
+            Some(pool) => pool.prune(),
+            None => break,
+        };
+        for (addr, count) in missing {
+            for _ in 0..count {
+                match socket::tcp_connect(addr, &options, bind.as_ref()).await {
+                    Ok(stream) => match pool.upgrade() {
+                        Some(pool) => pool.add(addr, stream),
+                        None => return,
+                    },
+                    Err(err) => {
+                        // Try again at the next interval.
+                        debug!("unable to connect to {} for pool: {}", addr, err);
+                        break;
+                    }
+                }
+            }
+        }
+        let _ = time::timeout(REPLENISH_INTERVAL, taken.notified()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    fn settings(min_idle: usize) -> WarmPool {
+        WarmPool {
+            min_idle,
+            max_age_ms: 60_000,
+            server_first_safe: false,
+        }
+    }
+
+    async fn wait_for_idle(pool: &ConnectionPool, addr: SocketAddr, count: usize) {
+        while pool.idle.lock().unwrap()[&addr].len() < count {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pool() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = ConnectionPool::start(&settings(2), &Default::default(), None, vec![addr]);
+        wait_for_idle(&pool, addr, 2).await;
+
+        // Connections are replaced after being handed out.
+        let (_server, _) = listener.accept().await.unwrap();
+        assert!(pool.take(addr).is_some());
+        wait_for_idle(&pool, addr, 2).await;
+        assert!(pool.take("127.0.0.1:1".parse().unwrap()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_server_first() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = ConnectionPool::start(&settings(1), &Default::default(), None, vec![addr]);
+        wait_for_idle(&pool, addr, 1).await;
+
+        // A connection where the server sent a greeting is discarded.
+        let (mut server, _) = listener.accept().await.unwrap();
+        server.write_all(b"220 ready\r\n").await.unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(pool.take(addr).is_none());
+    }
+}
@@ -0,0 +1,181 @@
+//! Mirroring of client traffic to a shadow destination.
+//!
+//! A shadow connection receives a copy of everything a client sends
+//! to the real destination, while the responses from the shadow are
+//! read and discarded. The data is handed to a background task, so a
+//! slow shadow never holds up the real connection. Instead, the
+//! shadow is dropped when too much data is waiting to be sent to it.
+
+use crate::{
+    protocol::socket,
+    session::{
+        rules::{OutboundBind, Shadow, SocketOptions},
+        Endpoint,
+    },
+};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncWriteExt},
+    sync::mpsc,
+    time,
+};
+
+/// Default number of bytes that can wait to be sent to the shadow.
+pub const DEFAULT_MAX_BUFFER: usize = 1024 * 1024;
+
+/// Time to keep reading responses from the shadow after the client
+/// has finished sending.
+const LINGER: Duration = Duration::from_secs(5);
+
+/// Connection mirroring client data to a shadow destination.
+pub struct Mirror {
+    destination: Endpoint,
+    sender: Mutex<Option<mpsc::UnboundedSender<Vec<u8>>>>,
+    /// Number of bytes waiting to be sent to the shadow.
+    pending: Arc<AtomicUsize>,
+    max_buffer: usize,
+}
+
+impl Mirror {
+    /// Start connecting to the shadow destination in the background.
+    pub fn start(
+        settings: &Shadow,
+        options: &SocketOptions,
+        bind: Option<&OutboundBind>,
+    ) -> Mirror {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(run(
+            settings.destination.clone(),
+            *options,
+            bind.copied(),
+            receiver,
+            pending.clone(),
+        ));
+        Mirror {
+            destination: settings.destination.clone(),
+            sender: Mutex::new(Some(sender)),
+            pending,
+            max_buffer: settings.max_buffer.unwrap_or(DEFAULT_MAX_BUFFER),
+        }
+    }
+
+    /// Queue data to be sent to the shadow. The shadow is dropped if it
+    /// has fallen too far behind or has failed.
+    pub fn send(&self, data: &[u8]) {
+        let mut sender = self.sender.lock().unwrap();
+        let queued = match sender.as_ref() {
+            Some(sender) if !data.is_empty() => {
+                let pending = self.pending.fetch_add(data.len(), Ordering::Relaxed) + data.len();
+                if pending > self.max_buffer {
+                    info!("shadow {} fell behind, dropping it", self.destination);
+                    false
+                } else {
+                    sender.send(data.to_vec()).is_ok()
+                }
+            }
+            _ => return,
+        };
+        if !queued {
+            *sender = None;
+        }
+    }
+
+    /// Check if data is still mirrored to the shadow.
+    pub fn is_active(&self) -> bool {
+        self.sender.lock().unwrap().is_some()
+    }
+}
+
+/// Connect to the shadow and send the queued data until the client is
+/// done or the shadow is dropped.
+async fn run(
+    destination: Endpoint,
+    options: SocketOptions,
+    bind: Option<OutboundBind>,
+    mut receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+    pending: Arc<AtomicUsize>,
+) {
+    let stream = match socket::connect(&destination, &options, bind.as_ref()).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            info!("unable to connect to shadow {}: {}", destination, err);
+            return;
+        }
+    };
+    let (mut reader, mut writer) = io::split(stream);
+    let sending = async {
+        while let Some(data) = receiver.recv().await {
+            writer.write_all(&data).await?;
+            pending.fetch_sub(data.len(), Ordering::Relaxed);
+        }
+        writer.shutdown().await
+    };
+    let result = tokio::select! {
+        result = sending => result,
+        _ = discard(&mut reader) => {
+            debug!("shadow {} closed the connection", destination);
+            return;
+        }
+    };
+    match result {
+        Ok(()) => {
+            let _ = time::timeout(LINGER, discard(&mut reader)).await;
+        }
+        Err(err) => info!("shadow {} failed: {}", destination, err),
+    }
+}
+
+/// Read and throw away everything from a reader.
+async fn discard<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<()> {
+    io::copy(reader, &mut io::sink()).await.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    fn settings(destination: std::net::SocketAddr, max_buffer: usize) -> Shadow {
+        Shadow {
+            destination: destination.into(),
+            max_buffer: Some(max_buffer),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mirror() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mirror = Mirror::start(&settings(addr, 1024), &Default::default(), None);
+        mirror.send(b"hello ");
+        mirror.send(b"world");
+        let (mut shadow, _) = listener.accept().await.unwrap();
+        shadow.write_all(b"ignored").await.unwrap();
+        drop(mirror);
+
+        let mut received = Vec::new();
+        shadow.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn test_fall_behind() {
+        // The background task does not get to run between the sends,
+        // so all data stays queued.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mirror = Mirror::start(&settings(addr, 8), &Default::default(), None);
+        mirror.send(b"1234");
+        assert!(mirror.is_active());
+        mirror.send(b"56789");
+        assert!(!mirror.is_active());
+        mirror.send(b"more");
+    }
+}
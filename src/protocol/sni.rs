@@ -0,0 +1,342 @@
+//! Server Name Indication support.
+//!
+//! Functions to extract the SNI hostname from a TLS ClientHello
+//! without terminating the TLS session, and a hostname map with
+//! wildcard support that is used to pick a destination pool based on
+//! the hostname.
+//!
+//! The ClientHello can be split over several TLS records and each
+//! record can arrive in several TCP segments, so the parser works on
+//! the bytes received so far and reports if more data is needed.
+
+use std::collections::HashMap;
+
+/// Content type for handshake records.
+const CONTENT_HANDSHAKE: u8 = 0x16;
+
+/// Handshake type for ClientHello.
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+
+/// Extension type for the server name extension.
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+
+/// Name type for host names in the server name extension.
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Maximum size of a ClientHello that we are prepared to buffer.
+pub const MAX_HELLO_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// More data is needed to parse the ClientHello.
+    Incomplete,
+    /// The data is not a TLS handshake.
+    NotTls,
+    /// The data is a TLS handshake, but is malformed.
+    Malformed(&'static str),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Extract the server name from the beginning of a TLS stream.
+///
+/// Returns the hostname if the buffer contains a complete ClientHello
+/// with a server name extension, `None` if it contains a complete
+/// ClientHello without a server name, and an error otherwise.
+pub fn server_name(buf: &[u8]) -> Result<Option<String>> {
+    let handshake = handshake_message(buf)?;
+    parse_client_hello(handshake)
+}
+
+/// Collect the first handshake message from the TLS records in the
+/// buffer. The message can span several records.
+fn handshake_message(mut buf: &[u8]) -> Result<Vec<u8>> {
+    let mut message = Vec::new();
+    loop {
+        if buf.is_empty() {
+            return Err(Error::Incomplete);
+        }
+        if buf[0] != CONTENT_HANDSHAKE {
+            return Err(Error::NotTls);
+        }
+        if buf.len() < 5 {
+            return Err(Error::Incomplete);
+        }
+        if buf[1] != 0x03 {
+            return Err(Error::NotTls);
+        }
+        let length = usize::from(u16::from_be_bytes([buf[3], buf[4]]));
+        if length == 0 {
+            return Err(Error::Malformed("empty handshake record"));
+        }
+        if buf.len() < 5 + length {
+            return Err(Error::Incomplete);
+        }
+        message.extend_from_slice(&buf[5..5 + length]);
+        buf = &buf[5 + length..];
+
+        if message.len() >= 4 {
+            if message[0] != HANDSHAKE_CLIENT_HELLO {
+                return Err(Error::Malformed("first handshake is not a ClientHello"));
+            }
+            let size = (usize::from(message[1]) << 16)
+                | (usize::from(message[2]) << 8)
+                | usize::from(message[3]);
+            if size > MAX_HELLO_SIZE {
+                return Err(Error::Malformed("ClientHello too large"));
+            }
+            if message.len() >= 4 + size {
+                message.truncate(4 + size);
+                message.drain(..4);
+                return Ok(message);
+            }
+        }
+    }
+}
+
+/// Simple reader over a byte slice that reports malformed data when
+/// reading past the end.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < count {
+            return Err(Error::Malformed("truncated ClientHello"));
+        }
+        let (head, tail) = self.buf.split_at(count);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Read a vector prefixed with an 8-bit length.
+    fn vec8(&mut self) -> Result<&'a [u8]> {
+        let length = usize::from(self.u8()?);
+        self.take(length)
+    }
+
+    /// Read a vector prefixed with a 16-bit length.
+    fn vec16(&mut self) -> Result<&'a [u8]> {
+        let length = usize::from(self.u16()?);
+        self.take(length)
+    }
+}
+
+fn parse_client_hello(hello: Vec<u8>) -> Result<Option<String>> {
+    let mut reader = Reader::new(&hello);
+    reader.take(2)?; // client_version
+    reader.take(32)?; // random
+    reader.vec8()?; // session_id
+    reader.vec16()?; // cipher_suites
+    reader.vec8()?; // compression_methods
+
+    // Extensions are optional, so a ClientHello without extensions
+    // is valid and does not have a server name.
+    if reader.is_empty() {
+        return Ok(None);
+    }
+
+    let mut extensions = Reader::new(reader.vec16()?);
+    while !extensions.is_empty() {
+        let kind = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if kind == EXTENSION_SERVER_NAME {
+            return parse_server_name(data).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+fn parse_server_name(data: &[u8]) -> Result<String> {
+    let mut reader = Reader::new(data);
+    let mut names = Reader::new(reader.vec16()?);
+    while !names.is_empty() {
+        let kind = names.u8()?;
+        let name = names.vec16()?;
+        if kind == NAME_TYPE_HOST_NAME {
+            return match std::str::from_utf8(name) {
+                Ok(name) if !name.is_empty() => Ok(name.to_ascii_lowercase()),
+                _ => Err(Error::Malformed("bad host name")),
+            };
+        }
+    }
+    Err(Error::Malformed("no host name in server name extension"))
+}
+
+/// Map from hostname patterns to values.
+///
+/// A pattern is either a full hostname, which matches only that
+/// hostname, or a wildcard pattern of the form `*.example.com`, which
+/// matches exactly one label in front of `example.com`. Matching is
+/// case-insensitive and exact matches take precedence over wildcard
+/// matches.
+pub struct HostMap<T> {
+    exact: HashMap<String, T>,
+    wildcard: HashMap<String, T>,
+}
+
+impl<T> HostMap<T> {
+    pub fn new() -> Self {
+        HostMap {
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+        }
+    }
+
+    /// Check that a hostname pattern is valid.
+    pub fn check_pattern(pattern: &str) -> std::result::Result<(), String> {
+        let name = pattern.strip_prefix("*.").unwrap_or(pattern);
+        if name.is_empty() || name.split('.').any(|label| label.is_empty()) {
+            return Err(format!("'{}' is not a valid hostname pattern", pattern));
+        }
+        if name.contains('*') {
+            return Err(format!(
+                "'{}' can only have a wildcard as the first label",
+                pattern
+            ));
+        }
+        Ok(())
+    }
+
+    /// Add a value for a hostname pattern.
+    pub fn insert(&mut self, pattern: &str, value: T) -> std::result::Result<(), String> {
+        Self::check_pattern(pattern)?;
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_prefix("*.") {
+            Some(suffix) => self.wildcard.insert(suffix.to_string(), value),
+            None => self.exact.insert(pattern, value),
+        };
+        Ok(())
+    }
+
+    /// Look up the value for a hostname.
+    pub fn get(&self, hostname: &str) -> Option<&T> {
+        let hostname = hostname.to_ascii_lowercase();
+        if let Some(value) = self.exact.get(&hostname) {
+            return Some(value);
+        }
+        let (_, suffix) = hostname.split_once('.')?;
+        self.wildcard.get(suffix)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcard.is_empty()
+    }
+}
+
+impl<T> Default for HostMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a ClientHello handshake message with an optional server
+    /// name.
+    fn client_hello(hostname: Option<&str>) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0); // session_id
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // cipher_suites
+        body.extend_from_slice(&[0x01, 0x00]); // compression_methods
+        let mut extensions = Vec::new();
+        if let Some(name) = hostname {
+            let name = name.as_bytes();
+            let mut list = vec![NAME_TYPE_HOST_NAME];
+            list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            list.extend_from_slice(name);
+            let mut data = (list.len() as u16).to_be_bytes().to_vec();
+            data.extend_from_slice(&list);
+            extensions.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+            extensions.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(&data);
+        }
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut message = vec![HANDSHAKE_CLIENT_HELLO, 0];
+        message.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        message.extend_from_slice(&body);
+        message
+    }
+
+    /// Wrap a handshake message in TLS records with at most
+    /// `fragment` bytes each.
+    fn records(message: &[u8], fragment: usize) -> Vec<u8> {
+        let mut result = Vec::new();
+        for chunk in message.chunks(fragment) {
+            result.extend_from_slice(&[CONTENT_HANDSHAKE, 0x03, 0x01]);
+            result.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            result.extend_from_slice(chunk);
+        }
+        result
+    }
+
+    #[test]
+    fn test_server_name() {
+        let stream = records(&client_hello(Some("Example.COM")), 1024);
+        assert_eq!(server_name(&stream), Ok(Some("example.com".to_string())));
+
+        let stream = records(&client_hello(None), 1024);
+        assert_eq!(server_name(&stream), Ok(None));
+    }
+
+    #[test]
+    fn test_fragmented() {
+        let stream = records(&client_hello(Some("example.com")), 7);
+        assert_eq!(server_name(&stream), Ok(Some("example.com".to_string())));
+        for length in 0..stream.len() {
+            assert_eq!(server_name(&stream[..length]), Err(Error::Incomplete));
+        }
+    }
+
+    #[test]
+    fn test_bad_hello() {
+        assert_eq!(server_name(b"GET / HTTP/1.1\r\n"), Err(Error::NotTls));
+
+        let mut message = client_hello(Some("example.com"));
+        message.truncate(50);
+        message[3] = 46;
+        assert!(matches!(
+            server_name(&records(&message, 1024)),
+            Err(Error::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_host_map() {
+        let mut map = HostMap::new();
+        map.insert("example.com", 1).unwrap();
+        map.insert("*.example.com", 2).unwrap();
+        map.insert("www.example.com", 3).unwrap();
+        assert_eq!(map.get("example.com"), Some(&1));
+        assert_eq!(map.get("foo.Example.com"), Some(&2));
+        assert_eq!(map.get("www.example.com"), Some(&3));
+        assert_eq!(map.get("a.b.example.com"), None);
+        assert_eq!(map.get("example.org"), None);
+        assert!(map.insert("foo.*.com", 4).is_err());
+        assert!(map.insert("*", 4).is_err());
+        assert!(map.insert("foo..com", 4).is_err());
+    }
+}
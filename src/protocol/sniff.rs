@@ -0,0 +1,256 @@
+//! Protocol sniffing.
+//!
+//! Picks a destination pool for a connection based on the first bytes
+//! sent by the client, so that several protocols can share a port.
+//! Routes are tried in order and each matcher reports if the bytes
+//! received so far match, do not match, or if it needs more data to
+//! decide. Reading stops as soon as a route can be picked, when the
+//! timeout expires, or when the maximum prefix size has been read.
+
+use crate::session::{
+    rules::{Sniff, SniffMatch},
+    Endpoint,
+};
+use regex::bytes::Regex;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time::{self, Instant},
+};
+
+/// Time to wait for the client if the rule does not give a timeout.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Maximum number of bytes read before picking a route.
+pub const MAX_PREFIX_SIZE: usize = 4096;
+
+/// Methods that can start an HTTP/1.x request, and the start of the
+/// HTTP/2 connection preface.
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"CONNECT ",
+    b"OPTIONS ",
+    b"TRACE ",
+    b"PATCH ",
+    b"PRI * HTTP/2.0",
+];
+
+/// Result of matching the bytes received so far.
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Match,
+    NoMatch,
+    Incomplete,
+}
+
+/// Compiled matcher for a route.
+#[derive(Debug)]
+pub enum Matcher {
+    Tls,
+    Http,
+    Ssh,
+    Regex(Regex),
+    Timeout,
+}
+
+impl Matcher {
+    pub fn new(matcher: &SniffMatch) -> Result<Matcher, regex::Error> {
+        Ok(match matcher {
+            SniffMatch::Tls => Matcher::Tls,
+            SniffMatch::Http => Matcher::Http,
+            SniffMatch::Ssh => Matcher::Ssh,
+            SniffMatch::Regex(pattern) => Matcher::Regex(Regex::new(pattern)?),
+            SniffMatch::Timeout => Matcher::Timeout,
+        })
+    }
+
+    /// Match the bytes received so far. If `complete` is true, no more
+    /// bytes will arrive.
+    fn check(&self, prefix: &[u8], complete: bool) -> Verdict {
+        match self {
+            // Handshake record with a TLS major version.
+            Matcher::Tls => starts_with(prefix, &[0x16, 0x03]),
+            Matcher::Http => {
+                let verdicts = HTTP_METHODS
+                    .iter()
+                    .map(|method| starts_with(prefix, method));
+                verdicts.min_by_key(Verdict::rank).unwrap()
+            }
+            Matcher::Ssh => starts_with(prefix, b"SSH-"),
+            Matcher::Regex(regex) if regex.is_match(prefix) => Verdict::Match,
+            Matcher::Regex(_) => Verdict::Incomplete,
+            // Only a client that has not sent anything can time out.
+            Matcher::Timeout if !prefix.is_empty() => Verdict::NoMatch,
+            Matcher::Timeout if complete => Verdict::Match,
+            Matcher::Timeout => Verdict::Incomplete,
+        }
+        .finish(complete)
+    }
+}
+
+impl Verdict {
+    /// Order verdicts so that a match is preferred over needing more
+    /// data, which is preferred over not matching.
+    fn rank(&self) -> u8 {
+        match self {
+            Verdict::Match => 0,
+            Verdict::Incomplete => 1,
+            Verdict::NoMatch => 2,
+        }
+    }
+
+    /// Decide a verdict when no more data will arrive.
+    fn finish(self, complete: bool) -> Verdict {
+        match self {
+            Verdict::Incomplete if complete => Verdict::NoMatch,
+            verdict => verdict,
+        }
+    }
+}
+
+/// Check if the prefix starts with the expected bytes.
+fn starts_with(prefix: &[u8], expected: &[u8]) -> Verdict {
+    if prefix.starts_with(expected) {
+        Verdict::Match
+    } else if expected.starts_with(prefix) {
+        Verdict::Incomplete
+    } else {
+        Verdict::NoMatch
+    }
+}
+
+/// Routes from matchers to destination pools.
+pub struct Sniffer<T> {
+    routes: Vec<(Matcher, T)>,
+    timeout: Duration,
+}
+
+impl<T> Sniffer<T> {
+    /// Create a sniffer for the settings, with a pool created for each
+    /// route.
+    pub fn new<F>(settings: &Sniff, mut make_pool: F) -> Result<Sniffer<T>, regex::Error>
+    where
+        F: FnMut(&[Endpoint]) -> T,
+    {
+        let mut routes = Vec::new();
+        for route in &settings.routes {
+            let matcher = Matcher::new(&route.matcher)?;
+            routes.push((matcher, make_pool(&route.destinations)));
+        }
+        Ok(Sniffer {
+            routes,
+            timeout: settings
+                .timeout_ms
+                .map_or(DEFAULT_TIMEOUT, Duration::from_millis),
+        })
+    }
+
+    /// Read from the client until a route can be picked and return the
+    /// pool of the route, or `None` if no route matches. All bytes read
+    /// are kept in `prefix` so that they can be replayed to the
+    /// destination.
+    pub async fn route<R>(&self, reader: &mut R, prefix: &mut Vec<u8>) -> Option<&T>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = [0; MAX_PREFIX_SIZE];
+        loop {
+            let complete = prefix.len() >= MAX_PREFIX_SIZE;
+            if let Some(pool) = self.decide(prefix, complete) {
+                return pool;
+            }
+            let room = MAX_PREFIX_SIZE - prefix.len();
+            match time::timeout_at(deadline, reader.read(&mut buf[..room])).await {
+                Ok(Ok(bytes)) if bytes > 0 => prefix.extend_from_slice(&buf[..bytes]),
+                _ => return self.decide(prefix, true).flatten(),
+            }
+        }
+    }
+
+    /// Pick a route for the prefix. Returns `None` if more data is
+    /// needed, which is never the case if `complete` is true.
+    fn decide(&self, prefix: &[u8], complete: bool) -> Option<Option<&T>> {
+        for (matcher, pool) in &self.routes {
+            match matcher.check(prefix, complete) {
+                Verdict::Match => return Some(Some(pool)),
+                Verdict::Incomplete => return None,
+                Verdict::NoMatch => {}
+            }
+        }
+        Some(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::rules::SniffRoute;
+    use tokio::io::{self, AsyncWriteExt};
+
+    fn sniffer() -> Sniffer<&'static str> {
+        let settings = Sniff {
+            timeout_ms: Some(50),
+            routes: [
+                SniffMatch::Tls,
+                SniffMatch::Http,
+                SniffMatch::Ssh,
+                SniffMatch::Regex(r"^\x00\x01".to_string()),
+                SniffMatch::Timeout,
+            ]
+            .iter()
+            .map(|matcher| SniffRoute {
+                matcher: matcher.clone(),
+                destinations: Vec::new(),
+            })
+            .collect(),
+        };
+        let mut names = ["tls", "http", "ssh", "regex", "timeout"].iter();
+        Sniffer::new(&settings, |_| *names.next().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_decide() {
+        let sniffer = sniffer();
+        let decide = |prefix: &[u8]| sniffer.decide(prefix, false).map(|pool| pool.copied());
+        assert_eq!(decide(b"\x16\x03\x01\x02\x00"), Some(Some("tls")));
+        assert_eq!(decide(b"GET / HTTP/1.1\r\n"), Some(Some("http")));
+        assert_eq!(decide(b"PRI * HTTP/2.0\r\n"), Some(Some("http")));
+        assert_eq!(decide(b"SSH-2.0-OpenSSH_8.9\r\n"), Some(Some("ssh")));
+        assert_eq!(decide(b"\x00\x01\x02"), Some(Some("regex")));
+        assert_eq!(decide(b"\x00\x02"), None);
+        assert_eq!(decide(b"PO"), None);
+        assert_eq!(decide(b""), None);
+        assert_eq!(sniffer.decide(b"\x00\x02", true), Some(None));
+        assert_eq!(sniffer.decide(b"", true), Some(Some(&"timeout")));
+    }
+
+    #[tokio::test]
+    async fn test_route() {
+        let sniffer = sniffer();
+
+        // The request line arrives in two parts.
+        let (mut client, mut inbound) = io::duplex(64);
+        let mut prefix = Vec::new();
+        let send = async {
+            client.write_all(b"PO").await.unwrap();
+            time::sleep(Duration::from_millis(10)).await;
+            client.write_all(b"ST /").await.unwrap();
+        };
+        let (pool, _) = futures::future::join(sniffer.route(&mut inbound, &mut prefix), send).await;
+        assert_eq!(pool, Some(&"http"));
+        assert_eq!(prefix, b"POST /");
+
+        // A silent client is routed after the timeout.
+        let (_client, mut inbound) = io::duplex(64);
+        let mut prefix = Vec::new();
+        assert_eq!(
+            sniffer.route(&mut inbound, &mut prefix).await,
+            Some(&"timeout")
+        );
+    }
+}
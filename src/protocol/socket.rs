@@ -0,0 +1,530 @@
+//! Socket creation with the socket options of a rule.
+//!
+//! Listening sockets and outbound sockets are created with the
+//! options set before they are bound or connected, and the options
+//! are applied to accepted connections as well.
+//!
+//! Outbound sockets are bound to the outbound bind address of the rule,
+//! if there is one, before they are connected or used to send.
+//!
+//! Unix domain sockets are created here too. The socket options only
+//! apply to IP sockets, while socket files created for Unix domain
+//! sockets get the permissions and ownership given in the rule.
+
+use crate::{
+    protocol::stream::{Listener, Stream},
+    session::{
+        rules::{OutboundBind, SocketFile, SocketOptions},
+        Endpoint,
+    },
+};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+#[cfg(unix)]
+use tokio::net::{UnixDatagram, UnixListener, UnixStream};
+
+/// Create a listening stream socket for an endpoint.
+pub fn listen(
+    endpoint: &Endpoint,
+    options: &SocketOptions,
+    file: Option<&SocketFile>,
+) -> io::Result<Listener> {
+    match endpoint {
+        Endpoint::Inet(addr) => tcp_listener(*addr, options).map(Listener::Tcp),
+        #[cfg(unix)]
+        _ => unix_listener(endpoint, file).map(Listener::Unix),
+        #[cfg(not(unix))]
+        _ => Err(unix_unsupported()),
+    }
+}
+
+/// Open a stream connection to an endpoint. The outbound bind address
+/// only applies to IP endpoints.
+pub async fn connect(
+    endpoint: &Endpoint,
+    options: &SocketOptions,
+    bind: Option<&OutboundBind>,
+) -> io::Result<Stream> {
+    match endpoint {
+        Endpoint::Inet(addr) => tcp_connect(*addr, options, bind).await.map(Stream::Tcp),
+        #[cfg(unix)]
+        _ => unix_connect(endpoint).await.map(Stream::Unix),
+        #[cfg(not(unix))]
+        _ => Err(unix_unsupported()),
+    }
+}
+
+/// Create a listening TCP socket.
+pub fn tcp_listener(addr: SocketAddr, options: &SocketOptions) -> io::Result<TcpListener> {
+    let socket = new_socket(addr, Type::STREAM, options)?;
+    socket.set_reuse_address(true)?;
+    configure_tcp(&socket, addr, options)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Create a bound UDP socket.
+pub fn udp_socket(addr: SocketAddr, options: &SocketOptions) -> io::Result<UdpSocket> {
+    let socket = new_socket(addr, Type::DGRAM, options)?;
+    configure(&socket, addr, options)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Create an unconnected UDP socket for sending to a destination,
+/// bound to the outbound bind address if there is one.
+pub fn udp_outbound(
+    destination: SocketAddr,
+    options: &SocketOptions,
+    bind: Option<&OutboundBind>,
+) -> io::Result<UdpSocket> {
+    let socket = new_socket(destination, Type::DGRAM, options)?;
+    configure(&socket, destination, options)?;
+    match bind {
+        Some(bind) => bind_outbound(&socket, bind)?,
+        None => {
+            let any: IpAddr = match destination {
+                SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+            };
+            socket.bind(&SocketAddr::new(any, 0).into())?;
+        }
+    }
+    UdpSocket::from_std(socket.into())
+}
+
+/// Open a TCP connection.
+pub async fn tcp_connect(
+    addr: SocketAddr,
+    options: &SocketOptions,
+    bind: Option<&OutboundBind>,
+) -> io::Result<TcpStream> {
+    if options.is_default() && bind.is_none() {
+        return TcpStream::connect(addr).await;
+    }
+    let socket = new_socket(addr, Type::STREAM, options)?;
+    configure_tcp(&socket, addr, options)?;
+    if let Some(bind) = bind {
+        bind_outbound(&socket, bind)?;
+    }
+    connect_socket(socket, addr).await
+}
+
+/// Bind an outbound socket to the outbound bind address. With a port
+/// range, each port is tried in turn until one is free, starting after
+/// the port picked last time so that ports are reused as late as
+/// possible.
+fn bind_outbound(socket: &Socket, bind: &OutboundBind) -> io::Result<()> {
+    static NEXT_PORT: AtomicUsize = AtomicUsize::new(0);
+    let range = match bind.port_range {
+        Some(range) => range,
+        None => return socket.bind(&SocketAddr::new(bind.address, 0).into()),
+    };
+    let count = usize::from(range.last - range.first) + 1;
+    let start = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+    for offset in 0..count {
+        let port = range.first + ((start + offset) % count) as u16;
+        match socket.bind(&SocketAddr::new(bind.address, port).into()) {
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => continue,
+            result => return result,
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("no free port between {} and {}", range.first, range.last),
+    ))
+}
+
+/// Apply the options to an accepted TCP connection.
+pub fn configure_accepted(stream: &TcpStream, options: &SocketOptions) -> io::Result<()> {
+    if options.is_default() {
+        return Ok(());
+    }
+    configure_tcp(&SockRef::from(stream), stream.local_addr()?, options)
+}
+
+#[cfg(unix)]
+async fn connect_socket(socket: Socket, addr: SocketAddr) -> io::Result<TcpStream> {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    // SAFETY: the descriptor is owned by the socket, which is consumed.
+    let socket = unsafe { tokio::net::TcpSocket::from_raw_fd(socket.into_raw_fd()) };
+    socket.connect(addr).await
+}
+
+#[cfg(not(unix))]
+async fn connect_socket(socket: Socket, addr: SocketAddr) -> io::Result<TcpStream> {
+    socket.set_nonblocking(false)?;
+    socket.connect(&addr.into())?;
+    TcpStream::from_std(socket.into())
+}
+
+fn new_socket(addr: SocketAddr, kind: Type, options: &SocketOptions) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), kind, None)?;
+    socket.set_nonblocking(true)?;
+    if let (SocketAddr::V6(_), Some(v6only)) = (addr, options.v6only) {
+        socket.set_only_v6(v6only)?;
+    }
+    Ok(socket)
+}
+
+/// Apply the options for all socket types.
+fn configure(socket: &Socket, addr: SocketAddr, options: &SocketOptions) -> io::Result<()> {
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(tos) = options.traffic_class() {
+        match addr {
+            SocketAddr::V4(_) => socket.set_tos(tos.into())?,
+            SocketAddr::V6(_) => set_traffic_class_v6(socket, tos)?,
+        }
+    }
+    Ok(())
+}
+
+/// Apply the options for TCP sockets.
+fn configure_tcp(socket: &Socket, addr: SocketAddr, options: &SocketOptions) -> io::Result<()> {
+    configure(socket, addr, options)?;
+    if let Some(nodelay) = options.nodelay {
+        socket.set_nodelay(nodelay)?;
+    }
+    if let Some(keepalive) = &options.keepalive {
+        let mut params = TcpKeepalive::new().with_time(Duration::from_millis(keepalive.idle_ms));
+        if let Some(interval) = keepalive.interval_ms {
+            params = params.with_interval(Duration::from_millis(interval));
+        }
+        if let Some(count) = keepalive.count {
+            params = params.with_retries(count);
+        }
+        socket.set_tcp_keepalive(&params)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_traffic_class_v6(socket: &Socket, tclass: u8) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let value = libc::c_int::from(tclass);
+    // SAFETY: the option value is a C integer, as expected for IPV6_TCLASS.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+fn set_traffic_class_v6(_socket: &Socket, _tclass: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "traffic class is not supported for IPv6",
+    ))
+}
+
+/// Create a listening Unix domain stream socket. A socket file left
+/// behind by an earlier listener is removed first.
+#[cfg(unix)]
+fn unix_listener(endpoint: &Endpoint, file: Option<&SocketFile>) -> io::Result<UnixListener> {
+    remove_stale_socket(endpoint)?;
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&unix_addr(endpoint)?)?;
+    set_file_attributes(endpoint, file)?;
+    socket.listen(1024)?;
+    UnixListener::from_std(socket.into())
+}
+
+/// Create a Unix domain datagram socket, which is bound to the
+/// endpoint if one is given.
+#[cfg(unix)]
+pub fn unix_datagram(
+    endpoint: Option<&Endpoint>,
+    file: Option<&SocketFile>,
+) -> io::Result<UnixDatagram> {
+    let socket = Socket::new(Domain::UNIX, Type::DGRAM, None)?;
+    socket.set_nonblocking(true)?;
+    if let Some(endpoint) = endpoint {
+        remove_stale_socket(endpoint)?;
+        socket.bind(&unix_addr(endpoint)?)?;
+        set_file_attributes(endpoint, file)?;
+    }
+    UnixDatagram::from_std(socket.into())
+}
+
+/// Send a datagram to a Unix domain socket endpoint.
+#[cfg(unix)]
+pub async fn unix_send_to(
+    socket: &UnixDatagram,
+    buf: &[u8],
+    endpoint: &Endpoint,
+) -> io::Result<usize> {
+    let addr = unix_addr(endpoint)?;
+    loop {
+        socket.writable().await?;
+        match SockRef::from(socket).send_to(buf, &addr) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn unix_connect(endpoint: &Endpoint) -> io::Result<UnixStream> {
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.set_nonblocking(true)?;
+    match socket.connect(&unix_addr(endpoint)?) {
+        Err(err) if err.raw_os_error() != Some(libc::EINPROGRESS) => return Err(err),
+        _ => {}
+    }
+    let stream = UnixStream::from_std(socket.into())?;
+    stream.writable().await?;
+    match stream.take_error()? {
+        Some(err) => Err(err),
+        None => Ok(stream),
+    }
+}
+
+/// Socket address of a Unix domain socket endpoint. Abstract names
+/// start with a null byte.
+#[cfg(unix)]
+fn unix_addr(endpoint: &Endpoint) -> io::Result<socket2::SockAddr> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+    match endpoint {
+        Endpoint::Unix(path) => socket2::SockAddr::unix(path),
+        Endpoint::Abstract(name) => {
+            let bytes = [b"\0", name.as_bytes()].concat();
+            socket2::SockAddr::unix(OsStr::from_bytes(&bytes))
+        }
+        Endpoint::Inet(addr) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a Unix domain socket", addr),
+        )),
+    }
+}
+
+/// Remove a socket file left behind at the path of an endpoint. Other
+/// files are left alone, so binding fails for them.
+#[cfg(unix)]
+fn remove_stale_socket(endpoint: &Endpoint) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Endpoint::Unix(path) = endpoint {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Set the permissions and ownership of the socket file of an
+/// endpoint.
+#[cfg(unix)]
+fn set_file_attributes(endpoint: &Endpoint, file: Option<&SocketFile>) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let (path, file) = match (endpoint, file) {
+        (Endpoint::Unix(path), Some(file)) => (path, file),
+        _ => return Ok(()),
+    };
+    let invalid = |err| io::Error::new(io::ErrorKind::InvalidInput, err);
+    if let Some(mode) = file.parse_mode().map_err(invalid)? {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    let owner = file.owner.as_deref().map(user_id).transpose()?;
+    let group = file.group.as_deref().map(group_id).transpose()?;
+    if owner.is_some() || group.is_some() {
+        std::os::unix::fs::chown(path, owner, group)?;
+    }
+    Ok(())
+}
+
+/// Look up a user by name or numeric id.
+#[cfg(unix)]
+pub fn user_id(user: &str) -> io::Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = std::ffi::CString::new(user)?;
+    let mut entry = std::mem::MaybeUninit::<libc::passwd>::uninit();
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+    // SAFETY: all pointers are valid and the buffer length is correct.
+    let error = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            entry.as_mut_ptr(),
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if result.is_null() {
+        return Err(lookup_error(error, "user", user));
+    }
+    // SAFETY: the entry was filled in since the result is not null.
+    Ok(unsafe { entry.assume_init() }.pw_uid)
+}
+
+/// Look up a group by name or numeric id.
+#[cfg(unix)]
+pub fn group_id(group: &str) -> io::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = std::ffi::CString::new(group)?;
+    let mut entry = std::mem::MaybeUninit::<libc::group>::uninit();
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+    // SAFETY: all pointers are valid and the buffer length is correct.
+    let error = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            entry.as_mut_ptr(),
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if result.is_null() {
+        return Err(lookup_error(error, "group", group));
+    }
+    // SAFETY: the entry was filled in since the result is not null.
+    Ok(unsafe { entry.assume_init() }.gr_gid)
+}
+
+#[cfg(unix)]
+fn lookup_error(error: libc::c_int, kind: &str, name: &str) -> io::Error {
+    match error {
+        0 => io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no {} named '{}'", kind, name),
+        ),
+        error => io::Error::from_raw_os_error(error),
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "Unix domain sockets are not supported",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::rules::Keepalive;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_options() {
+        let options = SocketOptions {
+            nodelay: Some(true),
+            keepalive: Some(Keepalive {
+                idle_ms: 30_000,
+                interval_ms: Some(5_000),
+                count: Some(3),
+            }),
+            recv_buffer_size: Some(65536),
+            dscp: Some(46),
+            ..Default::default()
+        };
+        let listener = tcp_listener("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, accepted) =
+            futures::future::join(tcp_connect(addr, &options, None), listener.accept()).await;
+        let (accepted, _) = accepted.unwrap();
+        configure_accepted(&accepted, &options).unwrap();
+        for stream in &[connected.unwrap(), accepted] {
+            let socket = SockRef::from(stream);
+            assert!(socket.nodelay().unwrap());
+            assert!(socket.keepalive().unwrap());
+            assert_eq!(socket.tos().unwrap(), 46 << 2);
+            assert!(socket.recv_buffer_size().unwrap() >= 65536);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outbound_bind() {
+        use crate::session::rules::PortRange;
+        let bind = OutboundBind {
+            address: "127.0.0.1".parse().unwrap(),
+            port_range: Some(PortRange {
+                first: 47100,
+                last: 47199,
+            }),
+        };
+        let in_range = |addr: SocketAddr| (47100..=47199).contains(&addr.port());
+        let options = SocketOptions::default();
+        let listener = tcp_listener("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, accepted) =
+            futures::future::join(tcp_connect(addr, &options, Some(&bind)), listener.accept())
+                .await;
+        assert!(in_range(connected.unwrap().local_addr().unwrap()));
+        assert!(in_range(accepted.unwrap().1));
+
+        let udp = udp_outbound(addr, &options, Some(&bind)).unwrap();
+        assert!(in_range(udp.local_addr().unwrap()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_unix() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("router-test-{}.sock", std::process::id()));
+        let file = SocketFile {
+            mode: Some("0600".to_string()),
+            ..Default::default()
+        };
+        for endpoint in &[
+            Endpoint::Unix(path.clone()),
+            Endpoint::Abstract(format!("router-test-{}", std::process::id())),
+        ] {
+            let listener = listen(endpoint, &Default::default(), Some(&file)).unwrap();
+            let (connected, accepted) = futures::future::join(
+                connect(endpoint, &Default::default(), None),
+                listener.accept(),
+            )
+            .await;
+            let (mut accepted, client) = accepted.unwrap();
+            assert_eq!(client, Endpoint::Unix(Default::default()));
+            connected.unwrap().write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+        }
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // A stale socket file is replaced and datagrams can be sent to
+        // it.
+        let receiver = unix_datagram(Some(&Endpoint::Unix(path.clone())), None).unwrap();
+        let sender = unix_datagram(None, None).unwrap();
+        unix_send_to(&sender, b"pong", &Endpoint::Unix(path.clone()))
+            .await
+            .unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(receiver.recv(&mut buf).await.unwrap(), 4);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+//! Zero-copy forwarding on Linux.
+//!
+//! Data is moved from one socket to another using `splice(2)` through
+//! a pipe, so the kernel never has to copy the data to userspace and
+//! back. Each direction of a connection uses a pipe of its own.
+
+use std::{
+    io,
+    net::Shutdown,
+    os::unix::io::{AsRawFd, RawFd},
+    ptr,
+};
+use tokio::{io::unix::AsyncFd, net::TcpStream};
+
+/// Socket that data can be spliced to and from.
+pub struct Socket {
+    fd: AsyncFd<std::net::TcpStream>,
+}
+
+impl Socket {
+    pub fn new(stream: TcpStream) -> io::Result<Socket> {
+        Ok(Socket {
+            fd: AsyncFd::new(stream.into_std()?)?,
+        })
+    }
+
+    /// Shut down the write side of the socket.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.fd.get_ref().shutdown(Shutdown::Write)
+    }
+}
+
+/// Pipe used to move data between sockets.
+pub struct Pipe {
+    read: RawFd,
+    write: RawFd,
+    /// Number of bytes in the pipe.
+    len: usize,
+}
+
+impl Pipe {
+    pub fn new() -> io::Result<Pipe> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe {
+            read: fds[0],
+            write: fds[1],
+            len: 0,
+        })
+    }
+
+    /// Move at most `max` bytes from the socket into the pipe, waiting
+    /// for the socket to become readable. Returns the number of bytes
+    /// moved, which is zero at end of file.
+    pub async fn fill(&mut self, socket: &Socket, max: usize) -> io::Result<usize> {
+        loop {
+            let mut guard = socket.fd.readable().await?;
+            let write = self.write;
+            if let Ok(result) = guard.try_io(|fd| splice(fd.as_raw_fd(), write, max)) {
+                let bytes = result?;
+                self.len += bytes;
+                return Ok(bytes);
+            }
+        }
+    }
+
+    /// Move all bytes in the pipe to the socket, waiting for the socket
+    /// to become writable as necessary.
+    pub async fn drain(&mut self, socket: &Socket) -> io::Result<()> {
+        while self.len > 0 {
+            let mut guard = socket.fd.writable().await?;
+            let (read, len) = (self.read, self.len);
+            if let Ok(result) = guard.try_io(|fd| splice(read, fd.as_raw_fd(), len)) {
+                match result? {
+                    0 => return Err(io::ErrorKind::WriteZero.into()),
+                    bytes => self.len -= bytes,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // SAFETY: the descriptors are owned by the pipe.
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    // SAFETY: null offsets are allowed and mean the current position.
+    let result = unsafe { libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), len, flags) };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result as usize)
+    }
+}
@@ -3,17 +3,157 @@
 //! A lot of the code is copied from the `proxy.rs` example in the
 //! Tokio examples directory.
 
-use crate::session::{strategy::Strategy, Rule};
+#[cfg(target_os = "linux")]
+use crate::protocol::splice;
+use crate::{
+    protocol::{
+        limit::Limiter,
+        pool::ConnectionPool,
+        shadow::Mirror,
+        sni::{self, HostMap},
+        sniff::Sniffer,
+        socket,
+        stream::Stream,
+        throttle::{ConnectionThrottle, Throttle},
+        tls::{Acceptor, Connector},
+        Error, Result,
+    },
+    session::{
+        registry::{Connection, Registry},
+        rules::{OutboundBind, Shadow, SocketOptions},
+        stats::RuleStats,
+        strategy::{Context, Outcome, Pick, Selection, Strategy, StrategyFactory, Tables},
+        Endpoint, Protocol, Rule,
+    },
+};
 use futures::{future, FutureExt};
-use std::{error, net::SocketAddr};
+use std::{
+    error,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 use tokio::{
-    io::{self, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::{self, Instant},
 };
+use tokio_rustls::server;
+
+/// Time to wait for the client to send a complete ClientHello when
+/// routing on server name.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Size of the buffer used when copying data between connections.
+const COPY_BUFFER_SIZE: usize = 16 * 1024;
+
+type StrategyRef = Mutex<Box<dyn Strategy + Send>>;
+
+/// State shared between all connections of a TCP session.
+struct Shared {
+    rule_id: usize,
+    default: StrategyRef,
+    sni: HostMap<StrategyRef>,
+    client_pools: HostMap<StrategyRef>,
+    sniffer: Option<Sniffer<StrategyRef>>,
+    acceptor: Option<Acceptor>,
+    connector: Option<Connector>,
+    limiter: Limiter,
+    timeouts: Timeouts,
+    zero_copy: bool,
+    socket_options: SocketOptions,
+    outbound_bind: Option<OutboundBind>,
+    pool: Option<Arc<ConnectionPool>>,
+    shadow: Option<Shadow>,
+    throttle: Arc<Throttle>,
+    registry: Arc<Registry>,
+    stats: Arc<RuleStats>,
+}
+
+impl Shared {
+    fn new(
+        rule_id: usize,
+        rule: &Rule,
+        strategy: Box<dyn Strategy + Send>,
+        stats: Arc<RuleStats>,
+        throttle: Arc<Throttle>,
+        registry: Arc<Registry>,
+        tables: &Tables,
+    ) -> Result<Shared> {
+        let sni = make_pools(rule, &rule.sni, tables);
+        let (acceptor, client_pools) = match &rule.tls {
+            Some(settings) => (
+                Some(Acceptor::new(settings)?),
+                make_pools(rule, &settings.client_pools, tables),
+            ),
+            None => (None, HostMap::new()),
+        };
+        let connector = match &rule.upstream_tls {
+            Some(settings) => Some(Connector::new(settings)?),
+            None => None,
+        };
+        let sniffer = match &rule.sniff {
+            Some(settings) => Some(
+                Sniffer::new(settings, |destinations| {
+                    Mutex::new(StrategyFactory::build(rule, destinations, tables))
+                })
+                .map_err(|err| Error::SniffError(err.to_string()))?,
+            ),
+            None => None,
+        };
+        Ok(Shared {
+            rule_id,
+            default: Mutex::new(strategy),
+            sni,
+            client_pools,
+            sniffer,
+            acceptor,
+            connector,
+            limiter: Limiter::new(rule, stats.clone()),
+            timeouts: Timeouts::new(rule),
+            zero_copy: rule.zero_copy,
+            socket_options: rule.socket_options,
+            outbound_bind: rule.outbound_bind,
+            pool: rule.warm_pool.map(|settings| {
+                let destinations = rule.all_destinations().into_iter().filter_map(|d| d.inet());
+                let bind = rule.outbound_bind.as_ref();
+                ConnectionPool::start(&settings, &rule.socket_options, bind, destinations)
+            }),
+            shadow: rule.shadow.clone(),
+            throttle,
+            registry,
+            stats,
+        })
+    }
+}
+
+/// Create a strategy for each pool in a map from hostname patterns to
+/// destinations.
+fn make_pools<'a, I>(rule: &Rule, pools: I, tables: &Tables) -> HostMap<StrategyRef>
+where
+    I: IntoIterator<Item = (&'a String, &'a Vec<Endpoint>)>,
+{
+    let mut map = HostMap::new();
+    for (pattern, destinations) in pools {
+        let strategy = StrategyFactory::build(rule, destinations, tables);
+        if let Err(err) = map.insert(pattern, Mutex::new(strategy)) {
+            warn!("ignoring pattern: {}", err);
+        }
+    }
+    map
+}
 
 pub struct TcpSession {
-    source: SocketAddr,
+    rule_id: usize,
+    rule: Rule,
     strategy: Box<dyn Strategy + Send>,
+    stats: Arc<RuleStats>,
+    throttle: Arc<Throttle>,
+    registry: Arc<Registry>,
+    tables: Tables,
 }
 
 /// A TCP session.
@@ -21,29 +161,54 @@ pub struct TcpSession {
 /// The TCP session will listen for connections on the provided port
 /// and send to the provided destination.
 impl TcpSession {
-    pub async fn new(rule: Rule, strategy: Box<dyn Strategy + Send>) -> TcpSession {
+    pub async fn new(
+        rule_id: usize,
+        rule: Rule,
+        strategy: Box<dyn Strategy + Send>,
+        stats: Arc<RuleStats>,
+        throttle: Arc<Throttle>,
+        registry: Arc<Registry>,
+        tables: Tables,
+    ) -> TcpSession {
         TcpSession {
-            source: rule.source,
+            rule_id,
+            rule,
             strategy,
+            stats,
+            throttle,
+            registry,
+            tables,
         }
     }
 
-    pub async fn start(self) -> Result<(), Box<dyn error::Error + Send>> {
+    pub async fn start(self) -> Result<()> {
         let TcpSession {
-            source,
-            mut strategy,
+            rule_id,
+            rule,
+            strategy,
+            stats,
+            throttle,
+            registry,
+            tables,
         } = self;
-        let listener = match TcpListener::bind(source).await {
-            Ok(listener) => listener,
-            Err(err) => return Err(Box::new(err)),
-        };
+        let shared = Arc::new(Shared::new(
+            rule_id, &rule, strategy, stats, throttle, registry, &tables,
+        )?);
+        let listener = socket::listen(
+            &rule.source,
+            &rule.socket_options,
+            rule.socket_file.as_ref(),
+        )?;
 
         info!("session started listening for connections");
         while let Ok((client, client_addr)) = listener.accept().await {
             info!("accepting connection from {}", client_addr);
-            let destinations = strategy.destinations();
-            assert!(destinations.len() == 1);
-            let transfer = transfer(client, destinations[0]).map(|result| {
+            if let Stream::Tcp(stream) = &client {
+                if let Err(err) = socket::configure_accepted(stream, &rule.socket_options) {
+                    warn!("unable to set socket options: {}", err);
+                }
+            }
+            let transfer = connect(client, client_addr, shared.clone()).map(|result| {
                 if let Err(err) = result {
                     debug!("Failed to transfer; error={}", err);
                 }
@@ -54,37 +219,651 @@ impl TcpSession {
     }
 }
 
+/// Pick a destination for the client and transfer data between them.
+///
+/// The connection is closed if it is over the connection limits of
+/// the rule. If the session terminates TLS, the handshake is done first and
+/// the destination pool is picked based on the client identity or the
+/// server name. Otherwise, if the session sniffs the protocol, the
+/// first bytes are read from the client and matched against the
+/// sniffing routes, and if the session routes on server name, the
+/// ClientHello is read from the client. The bytes read are replayed to
+/// the destination.
+async fn connect(
+    mut inbound: Stream,
+    client_addr: Endpoint,
+    shared: Arc<Shared>,
+) -> std::result::Result<(), Box<dyn error::Error>> {
+    let _permit = match shared.limiter.acquire(client_addr.ip()).await {
+        Ok(permit) => permit,
+        Err(reason) => {
+            info!("rejected connection from {}: {}", client_addr, reason);
+            return Ok(());
+        }
+    };
+    let connection = shared.registry.register(client_addr.clone());
+
+    if let Some(acceptor) = &shared.acceptor {
+        let accepted = match acceptor.accept(inbound).await {
+            Ok(accepted) => accepted,
+            Err(reason) => {
+                info!("rejected TLS handshake from {}: {}", client_addr, reason);
+                shared.stats.reject_handshake(reason);
+                return Ok(());
+            }
+        };
+        let by_identity = accepted.identity.as_ref().and_then(|identity| {
+            info!("client {} authenticated as {}", client_addr, identity);
+            identity.lookup(&shared.client_pools)
+        });
+        let by_name = accepted
+            .server_name
+            .as_ref()
+            .and_then(|name| shared.sni.get(name));
+        let pool = by_identity.or(by_name).unwrap_or(&shared.default);
+        return forward(accepted.stream, pool, &[], &connection, &shared).await;
+    }
+
+    let mut prefix = Vec::new();
+    let pool = if let Some(sniffer) = &shared.sniffer {
+        sniffer
+            .route(&mut inbound, &mut prefix)
+            .await
+            .unwrap_or(&shared.default)
+    } else if shared.sni.is_empty() {
+        &shared.default
+    } else {
+        let hostname = time::timeout(HELLO_TIMEOUT, read_server_name(&mut inbound, &mut prefix))
+            .await
+            .unwrap_or(Err(sni::Error::Incomplete));
+        match hostname {
+            Ok(Some(hostname)) => {
+                debug!("client requested server name '{}'", hostname);
+                shared.sni.get(&hostname).unwrap_or(&shared.default)
+            }
+            Ok(None) => {
+                debug!("no server name in ClientHello");
+                &shared.default
+            }
+            Err(err) => {
+                debug!("unable to read server name: {:?}", err);
+                &shared.default
+            }
+        }
+    };
+    forward(inbound, pool, &prefix, &connection, &shared).await
+}
+
+/// Connect to a destination from the pool, wrapping the connection in
+/// TLS if there is a connector, and transfer data. The reason the
+/// connection was closed is counted in the rule statistics.
+async fn forward<C>(
+    inbound: C,
+    pool: &StrategyRef,
+    prefix: &[u8],
+    connection: &Connection,
+    shared: &Shared,
+) -> std::result::Result<(), Box<dyn error::Error>>
+where
+    C: Inbound,
+{
+    let result = async {
+        let context = Context {
+            rule_id: shared.rule_id,
+            protocol: Protocol::Tcp,
+            client: connection.client(),
+            peeked: Some(prefix).filter(|prefix| !prefix.is_empty()),
+        };
+        let mut pick = Pick::default();
+        let selection = pool.lock().unwrap().select(&context, &mut pick);
+        let destination = match selection {
+            Selection::One(destination) => destination,
+            Selection::All(destinations) if !destinations.is_empty() => {
+                destinations.into_iter().next().unwrap()
+            }
+            _ => return Err("no destination available".into()),
+        };
+        let destination = &destination;
+        let picked = Picked {
+            pool,
+            pick,
+            destination: destination.clone(),
+        };
+        connection.set_backend(destination.clone());
+        let pooled = match (&shared.pool, destination.inet()) {
+            (Some(pool), Some(addr)) => pool.take(addr),
+            _ => None,
+        };
+        let outbound = match pooled {
+            Some(stream) => {
+                info!("using pooled connection to {}", destination);
+                Stream::Tcp(stream)
+            }
+            None => {
+                info!("connecting to {}", destination);
+                let bind = shared.outbound_bind.as_ref();
+                let started = Instant::now();
+                let connected = socket::connect(destination, &shared.socket_options, bind).await;
+                let outcome = match &connected {
+                    Ok(_) => Outcome::Connected(started.elapsed()),
+                    Err(_) => Outcome::Failed,
+                };
+                pool.lock()
+                    .unwrap()
+                    .outcome(&picked.pick, destination, outcome);
+                connected?
+            }
+        };
+        let controls = Controls::new(shared, connection);
+        match &shared.connector {
+            Some(connector) => {
+                let outbound = connector.connect(outbound).await?;
+                transfer(inbound, outbound, prefix, &controls).await
+            }
+            None if shared.zero_copy => match (inbound.into_tcp(), outbound) {
+                (Ok(inbound), Stream::Tcp(outbound)) => {
+                    transfer_zero_copy(inbound, outbound, prefix, &controls).await
+                }
+                (Ok(inbound), outbound) => transfer(inbound, outbound, prefix, &controls).await,
+                (Err(inbound), outbound) => transfer(inbound, outbound, prefix, &controls).await,
+            },
+            None => transfer(inbound, outbound, prefix, &controls).await,
+        }
+    }
+    .await;
+    let reason = *result.as_ref().unwrap_or(&"error");
+    info!("connection closed: {}", reason);
+    shared.stats.close_connection(reason);
+    result.map(|_| ())
+}
+
+/// Destination picked from a pool for a connection, with the pick
+/// of the selection. The pool is told that the connection has closed
+/// when this is dropped.
+struct Picked<'a> {
+    pool: &'a StrategyRef,
+    pick: Pick,
+    destination: Endpoint,
+}
+
+impl Drop for Picked<'_> {
+    fn drop(&mut self) {
+        let mut pool = self.pool.lock().unwrap();
+        pool.outcome(&self.pick, &self.destination, Outcome::Closed);
+    }
+}
+
+/// Connection from a client, which is either a plain stream or a TLS
+/// session.
+trait Inbound: AsyncRead + AsyncWrite + Unpin + Sized {
+    /// Get the TCP connection, if this is a plain TCP connection.
+    fn into_tcp(self) -> std::result::Result<TcpStream, Self>;
+}
+
+impl Inbound for Stream {
+    fn into_tcp(self) -> std::result::Result<TcpStream, Self> {
+        match self {
+            Stream::Tcp(stream) => Ok(stream),
+            #[cfg(unix)]
+            stream => Err(stream),
+        }
+    }
+}
+
+impl Inbound for server::TlsStream<Stream> {
+    fn into_tcp(self) -> std::result::Result<TcpStream, Self> {
+        Err(self)
+    }
+}
+
+/// Read from the client until a complete ClientHello has been seen
+/// and return the server name in it. All bytes read are kept in
+/// `prefix` so that they can be replayed to the destination.
+async fn read_server_name(
+    inbound: &mut Stream,
+    prefix: &mut Vec<u8>,
+) -> sni::Result<Option<String>> {
+    let mut buf = [0; 4096];
+    loop {
+        match sni::server_name(prefix) {
+            Err(sni::Error::Incomplete) if prefix.len() <= sni::MAX_HELLO_SIZE => {}
+            result => return result,
+        }
+        match inbound.read(&mut buf).await {
+            Ok(0) | Err(_) => return Err(sni::Error::Incomplete),
+            Ok(bytes) => prefix.extend_from_slice(&buf[..bytes]),
+        }
+    }
+}
+
 /// Set up a bidirectional connection.
 ///
-/// This is copied from the `proxy.rs` example in the Tokio examples
-/// directory.
+/// This is based on the `proxy.rs` example in the Tokio examples
+/// directory. Any bytes already read from the client are written to
+/// the destination before forwarding starts.
+///
+/// The connection is closed in both directions if there is no data
+/// transferred in either direction for the idle timeout, if the
+/// connection has been open for the maximum lifetime, or if the
+/// connection is killed through the registry. The reason for closing
+/// the connection is returned.
 ///
-/// Intention is to refactor this to allow some basic packet
-/// inspection to handle SSL connections.
-async fn transfer(
-    mut inbound: TcpStream,
-    proxy_addr: SocketAddr,
-) -> Result<(), Box<dyn error::Error>> {
-    info!("connecting to {}", proxy_addr);
-    let mut outbound = TcpStream::connect(proxy_addr).await?;
-
-    let (mut ri, mut wi) = inbound.split();
-    let (mut ro, mut wo) = outbound.split();
-
-    let client_to_server = async {
-        io::copy(&mut ri, &mut wo).await?;
-        info!("shutting down connection");
-        wo.shutdown().await
+/// Data in both directions is forwarded within the rate limits of the
+/// connection, and data from the client is mirrored to the shadow
+/// destination, if there is one.
+async fn transfer<C, S>(
+    inbound: C,
+    mut outbound: S,
+    prefix: &[u8],
+    controls: &Controls<'_>,
+) -> std::result::Result<&'static str, Box<dyn error::Error>>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    controls
+        .sent(&controls.connection.bytes_to_backend, prefix.len())
+        .await;
+    outbound.write_all(prefix).await?;
+    if let Some(mirror) = &controls.mirror {
+        mirror.send(prefix);
+    }
+
+    let (mut ri, mut wi) = io::split(inbound);
+    let (mut ro, mut wo) = io::split(outbound);
+
+    let reason = {
+        let to_backend = &controls.connection.bytes_to_backend;
+        let to_client = &controls.connection.bytes_to_client;
+        let mirror = controls.mirror.as_ref();
+        let client_to_server = copy(&mut ri, &mut wo, controls, to_backend, mirror);
+        let server_to_client = copy(&mut ro, &mut wi, controls, to_client, None);
+        let copying = future::try_join(client_to_server, server_to_client);
+        supervise(copying, controls).await?
     };
 
-    let server_to_client = async {
-        io::copy(&mut ro, &mut wi).await?;
-        info!("shutting down connection");
-        wi.shutdown().await
+    if reason != "closed" {
+        // The connection is closed anyway, so errors are ignored.
+        info!("shutting down connection: {}", reason);
+        let _ = future::join(wo.shutdown(), wi.shutdown()).await;
+    }
+
+    info!("session terminated");
+    Ok(reason)
+}
+
+/// Set up a bidirectional connection that moves data between the
+/// sockets without copying it to userspace.
+///
+/// This behaves like [`transfer`], but uses `splice(2)` on Linux. If
+/// splicing is not available, data is copied instead.
+#[cfg(target_os = "linux")]
+async fn transfer_zero_copy(
+    inbound: TcpStream,
+    mut outbound: TcpStream,
+    prefix: &[u8],
+    controls: &Controls<'_>,
+) -> std::result::Result<&'static str, Box<dyn error::Error>> {
+    let pipes = match (splice::Pipe::new(), splice::Pipe::new()) {
+        (Ok(first), Ok(second)) => (first, second),
+        (Err(err), _) | (_, Err(err)) => {
+            debug!("splice not available, copying instead: {}", err);
+            return transfer(inbound, outbound, prefix, controls).await;
+        }
     };
 
-    future::try_join(client_to_server, server_to_client).await?;
+    controls
+        .sent(&controls.connection.bytes_to_backend, prefix.len())
+        .await;
+    outbound.write_all(prefix).await?;
+
+    let inbound = splice::Socket::new(inbound)?;
+    let outbound = splice::Socket::new(outbound)?;
+
+    let reason = {
+        let to_backend = &controls.connection.bytes_to_backend;
+        let to_client = &controls.connection.bytes_to_client;
+        let client_to_server = splice_copy(pipes.0, &inbound, &outbound, controls, to_backend);
+        let server_to_client = splice_copy(pipes.1, &outbound, &inbound, controls, to_client);
+        let copying = future::try_join(client_to_server, server_to_client);
+        supervise(copying, controls).await?
+    };
+
+    if reason != "closed" {
+        // The connection is closed anyway, so errors are ignored.
+        info!("shutting down connection: {}", reason);
+        let _ = outbound.shutdown();
+        let _ = inbound.shutdown();
+    }
 
     info!("session terminated");
-    Ok(())
+    Ok(reason)
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn transfer_zero_copy(
+    inbound: TcpStream,
+    outbound: TcpStream,
+    prefix: &[u8],
+    controls: &Controls<'_>,
+) -> std::result::Result<&'static str, Box<dyn error::Error>> {
+    debug!("splice not available, copying instead");
+    transfer(inbound, outbound, prefix, controls).await
+}
+
+/// Wait for both directions of a connection to finish copying, for
+/// the connection to time out, or for the connection to be killed.
+/// Returns the reason that the connection was closed.
+async fn supervise<F>(copying: F, controls: &Controls<'_>) -> io::Result<&'static str>
+where
+    F: Future<Output = io::Result<(u64, u64)>>,
+{
+    tokio::select! {
+        result = copying => {
+            result?;
+            Ok("closed")
+        }
+        reason = watchdog(&controls.activity, controls.timeouts) => Ok(reason),
+        _ = controls.connection.killed() => Ok("killed"),
+    }
+}
+
+/// Splice data from a socket to another through a pipe until the
+/// reader reaches end of file and then shut down the writer.
+#[cfg(target_os = "linux")]
+async fn splice_copy(
+    mut pipe: splice::Pipe,
+    reader: &splice::Socket,
+    writer: &splice::Socket,
+    controls: &Controls<'_>,
+    counter: &AtomicU64,
+) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        let bytes = pipe.fill(reader, COPY_BUFFER_SIZE).await?;
+        if bytes == 0 {
+            break;
+        }
+        controls.sent(counter, bytes).await;
+        pipe.drain(writer).await?;
+        total += bytes as u64;
+    }
+    info!("shutting down connection");
+    writer.shutdown()?;
+    Ok(total)
+}
+
+/// Copy data from a reader to a writer until the reader reaches end
+/// of file and then shut down the writer. Each transfer is recorded
+/// on the connection and added to the counter, and the data is also
+/// sent to the mirror, if there is one.
+async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    controls: &Controls<'_>,
+    counter: &AtomicU64,
+    mirror: Option<&Mirror>,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    let mut total = 0;
+    loop {
+        let bytes = reader.read(&mut buf).await?;
+        if bytes == 0 {
+            break;
+        }
+        controls.sent(counter, bytes).await;
+        writer.write_all(&buf[..bytes]).await?;
+        if let Some(mirror) = mirror {
+            mirror.send(&buf[..bytes]);
+        }
+        total += bytes as u64;
+    }
+    info!("shutting down connection");
+    writer.shutdown().await?;
+    Ok(total)
+}
+
+/// Controls for a connection while data is transferred.
+struct Controls<'a> {
+    timeouts: Timeouts,
+    activity: Activity,
+    throttle: ConnectionThrottle,
+    connection: &'a Connection,
+    mirror: Option<Mirror>,
+}
+
+impl<'a> Controls<'a> {
+    /// Create the controls for a connection, which starts connecting
+    /// to the shadow destination if the rule has one.
+    fn new(shared: &Shared, connection: &'a Connection) -> Controls<'a> {
+        Controls {
+            timeouts: shared.timeouts,
+            activity: Activity::new(),
+            throttle: shared.throttle.connection(),
+            connection,
+            mirror: shared.shadow.as_ref().map(|settings| {
+                let bind = shared.outbound_bind.as_ref();
+                Mirror::start(settings, &shared.socket_options, bind)
+            }),
+        }
+    }
+
+    /// Wait until data can be sent within the rate limits, and record
+    /// it as activity on the connection and in the counter.
+    async fn sent(&self, counter: &AtomicU64, bytes: usize) {
+        self.throttle.wait(bytes).await;
+        self.activity.touch();
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Timeouts for proxied connections.
+#[derive(Clone, Copy)]
+struct Timeouts {
+    idle: Option<Duration>,
+    lifetime: Option<Duration>,
+}
+
+impl Timeouts {
+    fn new(rule: &Rule) -> Timeouts {
+        Timeouts {
+            idle: rule.idle_timeout_ms.map(Duration::from_millis),
+            lifetime: rule.max_lifetime_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+/// Activity on a connection.
+struct Activity {
+    start: Instant,
+    /// Time of the last transfer, in milliseconds since the start.
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// Record a transfer.
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Time of the last transfer.
+    fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
+
+/// Wait until the connection has been idle for too long or has
+/// reached the maximum lifetime and return the reason. Never returns
+/// if there are no timeouts.
+async fn watchdog(activity: &Activity, timeouts: Timeouts) -> &'static str {
+    loop {
+        let lifetime_deadline = timeouts.lifetime.map(|lifetime| activity.start + lifetime);
+        let idle_deadline = timeouts.idle.map(|idle| activity.last() + idle);
+        let now = Instant::now();
+        if lifetime_deadline.is_some_and(|deadline| deadline <= now) {
+            return "max-lifetime";
+        }
+        if idle_deadline.is_some_and(|deadline| deadline <= now) {
+            return "idle-timeout";
+        }
+        match lifetime_deadline.into_iter().chain(idle_deadline).min() {
+            Some(deadline) => time::sleep_until(deadline).await,
+            None => future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::registry::Registration;
+    use tokio::net::TcpListener;
+
+    fn register() -> Registration {
+        Arc::new(Registry::new()).register("127.0.0.1:4711".parse().unwrap())
+    }
+
+    fn controls(
+        connection: &Connection,
+        idle_ms: Option<u64>,
+        lifetime_ms: Option<u64>,
+    ) -> Controls<'_> {
+        Controls {
+            timeouts: Timeouts {
+                idle: idle_ms.map(Duration::from_millis),
+                lifetime: lifetime_ms.map(Duration::from_millis),
+            },
+            activity: Activity::new(),
+            throttle: Arc::new(Throttle::default()).connection(),
+            connection,
+            mirror: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeouts() {
+        let connection = register();
+        let (inbound, _client) = io::duplex(64);
+        let (outbound, _server) = io::duplex(64);
+        let idle = controls(&connection, Some(20), None);
+        let reason = transfer(inbound, outbound, &[], &idle).await;
+        assert_eq!(reason.unwrap(), "idle-timeout");
+
+        // Traffic keeps the connection alive until the maximum lifetime.
+        let (inbound, mut client) = io::duplex(64);
+        let (outbound, mut server) = io::duplex(64);
+        let traffic = async {
+            loop {
+                client.write_all(b"ping").await.unwrap();
+                server.read_exact(&mut [0; 4]).await.unwrap();
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        let limited = controls(&connection, Some(20), Some(100));
+        let reason = tokio::select! {
+            reason = transfer(inbound, outbound, &[], &limited) => reason,
+            _ = traffic => unreachable!(),
+        };
+        assert_eq!(reason.unwrap(), "max-lifetime");
+        assert!(connection.bytes_to_backend.load(Ordering::Relaxed) > 0);
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let connection = register();
+        let (inbound, client) = io::duplex(64);
+        let (outbound, server) = io::duplex(64);
+        drop((client, server));
+        let controls = controls(&connection, Some(20), None);
+        let reason = transfer(inbound, outbound, &[], &controls).await;
+        assert_eq!(reason.unwrap(), "closed");
+    }
+
+    #[tokio::test]
+    async fn test_killed() {
+        let registry = Arc::new(Registry::new());
+        let connection = registry.register("127.0.0.1:4711".parse().unwrap());
+        let (inbound, _client) = io::duplex(64);
+        let (outbound, _server) = io::duplex(64);
+        let controls = controls(&connection, None, None);
+        assert!(registry.kill(connection.id()));
+        let reason = transfer(inbound, outbound, &[], &controls).await;
+        assert_eq!(reason.unwrap(), "killed");
+    }
+
+    #[tokio::test]
+    async fn test_shadow() {
+        let connection = register();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let shadow = Shadow {
+            destination: listener.local_addr().unwrap().into(),
+            max_buffer: None,
+        };
+        let (inbound, mut client) = io::duplex(64);
+        let (outbound, mut server) = io::duplex(64);
+        let mut controls = controls(&connection, None, None);
+        controls.mirror = Some(Mirror::start(&shadow, &Default::default(), None));
+        let exchange = async {
+            client.write_all(b"world").await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, b"hello world");
+            server.shutdown().await.unwrap();
+        };
+        let transfer = transfer(inbound, outbound, b"hello ", &controls);
+        let (reason, _) = future::join(transfer, exchange).await;
+        assert_eq!(reason.unwrap(), "closed");
+        drop(controls);
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut mirrored = Vec::new();
+        stream.read_to_end(&mut mirrored).await.unwrap();
+        assert_eq!(mirrored, b"hello world");
+    }
+
+    /// Create a connected pair of TCP streams.
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, accepted) = future::join(TcpStream::connect(addr), listener.accept()).await;
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_zero_copy() {
+        let connection = register();
+        let (mut client, inbound) = tcp_pair().await;
+        let (outbound, mut server) = tcp_pair().await;
+        let controls = controls(&connection, None, None);
+        let transfer = transfer_zero_copy(inbound, outbound, b"hello ", &controls);
+        let exchange = async {
+            client.write_all(b"world").await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, b"hello world");
+            server.write_all(b"reply").await.unwrap();
+            server.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, b"reply");
+        };
+        let (reason, _) = future::join(transfer, exchange).await;
+        assert_eq!(reason.unwrap(), "closed");
+        assert_eq!(connection.bytes_to_backend.load(Ordering::Relaxed), 11);
+        assert_eq!(connection.bytes_to_client.load(Ordering::Relaxed), 5);
+    }
 }
@@ -0,0 +1,200 @@
+//! Bandwidth throttling.
+//!
+//! Data is forwarded at a limited rate using token buckets, where
+//! each byte forwarded takes a token. Tokens are added at a fixed
+//! rate up to the burst size. A transfer that takes more tokens than
+//! available is allowed, but the bucket goes into debt and the sender
+//! has to wait until the debt is paid off.
+//!
+//! Each rule has a [`Throttle`] with one bucket shared by all
+//! connections of the rule, and each connection has a
+//! [`ConnectionThrottle`] with a bucket of its own. The limits can be
+//! changed while connections are active.
+
+use crate::session::rules::{RateLimit, RateLimits};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::time;
+
+/// Token bucket for a rate limit.
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> TokenBucket {
+        TokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Change the limit, keeping the tokens collected so far.
+    fn set_limit(&mut self, limit: RateLimit) {
+        self.refill();
+        self.limit = limit;
+        self.tokens = self.tokens.min(limit.burst as f64);
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        let tokens = self.tokens + elapsed * self.limit.bytes_per_second as f64;
+        self.tokens = tokens.min(self.limit.burst as f64);
+        self.updated = now;
+    }
+
+    /// Take tokens for a number of bytes and return the time to wait
+    /// before the bytes can be sent.
+    fn take(&mut self, bytes: usize) -> Duration {
+        self.refill();
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.limit.bytes_per_second as f64)
+        }
+    }
+}
+
+/// Update a bucket to match a limit, creating or removing the bucket
+/// as necessary.
+fn update_bucket(bucket: &mut Option<TokenBucket>, limit: Option<RateLimit>) {
+    match (bucket.as_mut(), limit) {
+        (Some(current), Some(limit)) if current.limit != limit => current.set_limit(limit),
+        (Some(_), Some(_)) => {}
+        (None, Some(limit)) => *bucket = Some(TokenBucket::new(limit)),
+        (_, None) => *bucket = None,
+    }
+}
+
+/// Take tokens from a bucket, if there is one.
+fn take(bucket: &mut Option<TokenBucket>, bytes: usize) -> Duration {
+    bucket
+        .as_mut()
+        .map_or(Duration::from_secs(0), |bucket| bucket.take(bytes))
+}
+
+#[derive(Default)]
+struct Limits {
+    rule: Option<TokenBucket>,
+    per_connection: Option<RateLimit>,
+}
+
+/// Rate limits for a rule.
+#[derive(Default)]
+pub struct Throttle {
+    limits: Mutex<Limits>,
+}
+
+impl Throttle {
+    pub fn new(limits: Option<&RateLimits>) -> Throttle {
+        let throttle = Throttle::default();
+        throttle.set_limits(limits);
+        throttle
+    }
+
+    /// Change the rate limits. Active connections pick up the new
+    /// limits the next time they transfer data.
+    pub fn set_limits(&self, limits: Option<&RateLimits>) {
+        let mut current = self.limits.lock().unwrap();
+        update_bucket(&mut current.rule, limits.and_then(|limits| limits.per_rule));
+        current.per_connection = limits.and_then(|limits| limits.per_connection);
+    }
+
+    /// Create a throttle for a new connection of the rule.
+    pub fn connection(self: &Arc<Self>) -> ConnectionThrottle {
+        ConnectionThrottle {
+            throttle: self.clone(),
+            bucket: Mutex::new(None),
+        }
+    }
+
+    /// Wait until a number of bytes can be sent within the rule limit.
+    pub async fn wait(&self, bytes: usize) {
+        let delay = take(&mut self.limits.lock().unwrap().rule, bytes);
+        sleep(delay).await;
+    }
+}
+
+/// Rate limits for a connection, which are applied together with the
+/// limit for the rule.
+pub struct ConnectionThrottle {
+    throttle: Arc<Throttle>,
+    bucket: Mutex<Option<TokenBucket>>,
+}
+
+impl ConnectionThrottle {
+    /// Wait until a number of bytes can be sent within both the
+    /// connection limit and the rule limit.
+    pub async fn wait(&self, bytes: usize) {
+        let (rule_delay, per_connection) = {
+            let mut limits = self.throttle.limits.lock().unwrap();
+            (take(&mut limits.rule, bytes), limits.per_connection)
+        };
+        let connection_delay = {
+            let mut bucket = self.bucket.lock().unwrap();
+            update_bucket(&mut bucket, per_connection);
+            take(&mut bucket, bytes)
+        };
+        sleep(rule_delay.max(connection_delay)).await;
+    }
+}
+
+async fn sleep(delay: Duration) {
+    if delay > Duration::from_secs(0) {
+        time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(per_rule: Option<u64>, per_connection: Option<u64>) -> RateLimits {
+        let limit = |bytes_per_second| RateLimit {
+            bytes_per_second,
+            burst: 1000,
+        };
+        RateLimits {
+            per_rule: per_rule.map(limit),
+            per_connection: per_connection.map(limit),
+        }
+    }
+
+    #[test]
+    fn test_bucket() {
+        let mut bucket = TokenBucket::new(RateLimit {
+            bytes_per_second: 1000,
+            burst: 500,
+        });
+        assert_eq!(bucket.take(500), Duration::from_secs(0));
+        let delay = bucket.take(500);
+        assert!(delay > Duration::from_millis(400) && delay <= Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_throttle() {
+        let throttle = Arc::new(Throttle::new(Some(&limits(Some(100_000), Some(10_000)))));
+        let connection = throttle.connection();
+
+        // The burst is sent immediately, and after that the
+        // connection limit applies.
+        let start = Instant::now();
+        connection.wait(1000).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        connection.wait(500).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // Removing the limits applies to active connections.
+        throttle.set_limits(None);
+        let start = Instant::now();
+        connection.wait(100_000).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,310 @@
+//! TLS support.
+//!
+//! Functions to build TLS configurations from the settings in a rule
+//! and to wrap connections in TLS.
+
+use crate::{
+    protocol::{sni::HostMap, stream::Stream, Error, Result},
+    session::rules::{ListenerTls, UpstreamTls},
+};
+use rustls::{
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, NoClientAuth, PrivateKey,
+    RootCertStore, ServerConfig, Session, TLSError,
+};
+use std::{fs::File, io, io::BufReader, path::Path, sync::Arc, time::Duration};
+use tokio::time;
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+use webpki::DNSNameRef;
+use x509_parser::{extensions::GeneralName, parse_x509_certificate};
+
+/// Time to wait for a client to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read all certificates from a PEM file.
+pub fn read_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path).map_err(|err| tls_error(path, err))?);
+    let certificates =
+        certs(&mut reader).map_err(|_| tls_error(path, "unable to parse certificates"))?;
+    if certificates.is_empty() {
+        return Err(tls_error(path, "no certificates found"));
+    }
+    Ok(certificates)
+}
+
+/// Read the first private key from a PEM file. Both PKCS#8 and RSA
+/// keys are supported.
+pub fn read_private_key(path: &Path) -> Result<PrivateKey> {
+    let open = || File::open(path).map(BufReader::new);
+    let mut reader = open().map_err(|err| tls_error(path, err))?;
+    let mut keys =
+        pkcs8_private_keys(&mut reader).map_err(|_| tls_error(path, "unable to parse key"))?;
+    if keys.is_empty() {
+        let mut reader = open().map_err(|err| tls_error(path, err))?;
+        keys = rsa_private_keys(&mut reader).map_err(|_| tls_error(path, "unable to parse key"))?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| tls_error(path, "no private key found"))
+}
+
+/// Read a certificate store from a PEM file with CA certificates.
+pub fn read_root_store(path: &Path) -> Result<RootCertStore> {
+    let mut store = RootCertStore::empty();
+    for certificate in read_certificates(path)? {
+        store
+            .add(&certificate)
+            .map_err(|err| tls_error(path, format!("{:?}", err)))?;
+    }
+    Ok(store)
+}
+
+fn tls_error<T: std::fmt::Display>(path: &Path, err: T) -> Error {
+    Error::TlsError(format!("{}: {}", path.display(), err))
+}
+
+/// Connector that originates TLS sessions to destinations.
+#[derive(Clone)]
+pub struct Connector {
+    connector: TlsConnector,
+    server_name: String,
+}
+
+impl Connector {
+    /// Create a connector from the upstream TLS settings of a rule.
+    pub fn new(settings: &UpstreamTls) -> Result<Connector> {
+        let mut config = ClientConfig::new();
+        config.root_store = read_root_store(&settings.ca_file)?;
+        if let (Some(cert_file), Some(key_file)) = (&settings.cert_file, &settings.key_file) {
+            let certificates = read_certificates(cert_file)?;
+            let key = read_private_key(key_file)?;
+            config
+                .set_single_client_cert(certificates, key)
+                .map_err(|err| tls_error(cert_file, err))?;
+        }
+        DNSNameRef::try_from_ascii_str(&settings.server_name).map_err(|_| {
+            Error::TlsError(format!(
+                "'{}' is not a valid server name",
+                settings.server_name
+            ))
+        })?;
+        Ok(Connector {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name: settings.server_name.clone(),
+        })
+    }
+
+    /// Wrap an outbound connection in TLS and verify the server.
+    pub async fn connect(&self, stream: Stream) -> io::Result<client::TlsStream<Stream>> {
+        // The name was checked when the connector was created.
+        let name = DNSNameRef::try_from_ascii_str(&self.server_name).unwrap();
+        self.connector.connect(name, stream).await
+    }
+}
+
+/// Identity of a client that presented a certificate.
+///
+/// The names are the subject common names followed by the DNS, email
+/// and URI subject alternative names of the certificate.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Identity {
+    pub names: Vec<String>,
+}
+
+impl Identity {
+    /// Extract the identity from a DER-encoded certificate.
+    pub fn from_certificate(certificate: &Certificate) -> Option<Identity> {
+        let (_, certificate) = parse_x509_certificate(&certificate.0).ok()?;
+        let mut names: Vec<String> = certificate
+            .subject()
+            .iter_common_name()
+            .filter_map(|name| name.as_str().ok())
+            .map(|name| name.to_string())
+            .collect();
+        if let Ok(Some(extension)) = certificate.subject_alternative_name() {
+            for name in &extension.value.general_names {
+                match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => names.push(name.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        Some(Identity { names })
+    }
+
+    /// Look up the names of the identity in a host map and return the
+    /// value for the first name that matches.
+    pub fn lookup<'a, T>(&self, map: &'a HostMap<T>) -> Option<&'a T> {
+        self.names.iter().find_map(|name| map.get(name))
+    }
+}
+
+impl std::fmt::Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.names.join(", "))
+    }
+}
+
+/// Accepted TLS connection from a client.
+pub struct Accepted {
+    pub stream: server::TlsStream<Stream>,
+    /// Identity of the client, if it presented a certificate.
+    pub identity: Option<Identity>,
+    /// Server name sent by the client, if any.
+    pub server_name: Option<String>,
+}
+
+/// Acceptor that terminates TLS sessions from clients.
+#[derive(Clone)]
+pub struct Acceptor {
+    acceptor: TlsAcceptor,
+    client_names: Arc<HostMap<()>>,
+}
+
+impl Acceptor {
+    /// Create an acceptor from the listener TLS settings of a rule.
+    pub fn new(settings: &ListenerTls) -> Result<Acceptor> {
+        let mut config = match &settings.client_ca_file {
+            Some(ca_file) => {
+                ServerConfig::new(AllowAnyAuthenticatedClient::new(read_root_store(ca_file)?))
+            }
+            None => ServerConfig::new(NoClientAuth::new()),
+        };
+        let certificates = read_certificates(&settings.cert_file)?;
+        let key = read_private_key(&settings.key_file)?;
+        config
+            .set_single_cert(certificates, key)
+            .map_err(|err| tls_error(&settings.cert_file, err))?;
+        let mut client_names = HostMap::new();
+        for pattern in &settings.client_names {
+            client_names.insert(pattern, ()).map_err(Error::TlsError)?;
+        }
+        Ok(Acceptor {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            client_names: Arc::new(client_names),
+        })
+    }
+
+    /// Perform the TLS handshake with a client and check the client
+    /// identity.
+    ///
+    /// If the handshake is rejected, the error contains the reason
+    /// for the rejection.
+    pub async fn accept(&self, stream: Stream) -> std::result::Result<Accepted, &'static str> {
+        let stream = match time::timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(err)) => return Err(rejection_reason(&err)),
+            Err(_) => return Err("timeout"),
+        };
+        let (_, session) = stream.get_ref();
+        let identity = session
+            .get_peer_certificates()
+            .and_then(|certificates| certificates.first().and_then(Identity::from_certificate));
+        let server_name = session.get_sni_hostname().map(|name| name.to_string());
+        if !self.client_names.is_empty() {
+            match &identity {
+                Some(identity) if identity.lookup(&self.client_names).is_some() => {}
+                _ => return Err("identity-mismatch"),
+            }
+        }
+        Ok(Accepted {
+            stream,
+            identity,
+            server_name,
+        })
+    }
+}
+
+/// Classify a failed handshake.
+fn rejection_reason(err: &io::Error) -> &'static str {
+    match err.get_ref().and_then(|err| err.downcast_ref::<TLSError>()) {
+        Some(TLSError::NoCertificatesPresented) => "no-certificate",
+        Some(TLSError::WebPKIError(_)) => "bad-certificate",
+        Some(_) => "handshake-failed",
+        None => "io-error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::BTreeMap, path::PathBuf};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn cert_file(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/certs")
+            .join(name)
+    }
+
+    fn listener_tls(client_names: &[&str]) -> ListenerTls {
+        ListenerTls {
+            cert_file: cert_file("server.pem"),
+            key_file: cert_file("server.key"),
+            client_ca_file: Some(cert_file("ca.pem")),
+            client_names: client_names.iter().map(|name| name.to_string()).collect(),
+            client_pools: BTreeMap::new(),
+        }
+    }
+
+    fn upstream_tls(with_certificate: bool) -> UpstreamTls {
+        UpstreamTls {
+            ca_file: cert_file("ca.pem"),
+            server_name: "backend.example.com".to_string(),
+            cert_file: Some(cert_file("client.pem")).filter(|_| with_certificate),
+            key_file: Some(cert_file("client.key")).filter(|_| with_certificate),
+        }
+    }
+
+    /// Do a handshake between a connector and an acceptor and return
+    /// the result from the acceptor.
+    async fn handshake(
+        acceptor: Acceptor,
+        connector: Connector,
+    ) -> std::result::Result<(Option<Identity>, Option<String>), &'static str> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            acceptor
+                .accept(Stream::Tcp(stream))
+                .await
+                .map(|accepted| (accepted.identity, accepted.server_name))
+        });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let _client = connector.connect(Stream::Tcp(stream)).await;
+        server.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let acceptor = Acceptor::new(&listener_tls(&["*.example.com"])).unwrap();
+        let connector = Connector::new(&upstream_tls(true)).unwrap();
+        let (identity, server_name) = handshake(acceptor, connector).await.unwrap();
+        assert_eq!(
+            identity.unwrap().names,
+            vec![
+                "client.example.com",
+                "client.example.com",
+                "client@example.com"
+            ]
+        );
+        assert_eq!(server_name, Some("backend.example.com".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_rejected() {
+        let acceptor = Acceptor::new(&listener_tls(&[])).unwrap();
+        let connector = Connector::new(&upstream_tls(false)).unwrap();
+        assert_eq!(handshake(acceptor, connector).await, Err("no-certificate"));
+
+        let acceptor = Acceptor::new(&listener_tls(&["other.example.org"])).unwrap();
+        let connector = Connector::new(&upstream_tls(true)).unwrap();
+        assert_eq!(
+            handshake(acceptor, connector).await,
+            Err("identity-mismatch")
+        );
+    }
+}
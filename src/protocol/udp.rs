@@ -13,25 +13,80 @@
 // permissions and limitations under the License.
 
 use crate::{
-    protocol::Result,
-    session::{strategy::Strategy, Rule},
+    protocol::{socket, throttle::Throttle, Result},
+    session::{
+        rules::{OutboundBind, SocketFile, SocketOptions},
+        strategy::{Context, Outcome, Pick, Strategy},
+        Endpoint, Protocol, Rule,
+    },
 };
 use log::debug;
-use std::net::SocketAddr;
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::net::UdpSocket;
+#[cfg(unix)]
+use tokio::net::UnixDatagram;
+
+/// Time after which a destination that has not replied to a packet is
+/// taken to have lost it.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub struct UdpSession {
-    source: SocketAddr,
+    rule_id: usize,
+    source: Endpoint,
+    options: SocketOptions,
+    socket_file: Option<SocketFile>,
+    outbound_bind: Option<OutboundBind>,
+    destinations: Vec<Endpoint>,
     strategy: Box<dyn Strategy + Send>,
+    throttle: Arc<Throttle>,
+}
+
+/// A packet received by a session.
+enum Packet {
+    /// A packet from a client, with its size.
+    Client(usize, Endpoint),
+    /// A reply from a destination.
+    Reply(Endpoint),
 }
 
 /// An UDP session that will listen on one socket and send the packets
-/// to one or more other sockets.
+/// to one or more other sockets. Packets are sent within the rate
+/// limit of the rule.
+///
+/// The source and the destinations can be UDP sockets or Unix domain
+/// datagram sockets. Packets are sent from the source socket when the
+/// destination is of the same family, and from a separate socket
+/// otherwise. UDP packets are also sent from a separate socket when
+/// the rule has an outbound bind address.
+///
+/// If the strategy measures replies, packets from destinations are
+/// replies, which are not forwarded. The time from the first packet
+/// sent to a destination until its reply is reported to the strategy
+/// as the response time of the destination, and a destination that
+/// does not reply within the reply timeout is reported as failed.
 impl UdpSession {
-    pub async fn new(rule: &Rule, strategy: Box<dyn Strategy + Send>) -> UdpSession {
+    pub async fn new(
+        rule_id: usize,
+        rule: &Rule,
+        strategy: Box<dyn Strategy + Send>,
+        throttle: Arc<Throttle>,
+    ) -> UdpSession {
         UdpSession {
-            source: rule.source,
+            rule_id,
+            source: rule.source.clone(),
+            options: rule.socket_options,
+            socket_file: rule.socket_file.clone(),
+            outbound_bind: rule.outbound_bind,
+            destinations: rule.all_destinations(),
             strategy,
+            throttle,
         }
     }
 
@@ -41,26 +96,231 @@ impl UdpSession {
     /// shutdown.
     pub async fn start(self) -> Result<()> {
         let UdpSession {
+            rule_id,
             source,
+            options,
+            socket_file,
+            outbound_bind,
+            destinations,
             mut strategy,
+            throttle,
         } = self;
 
-        let socket = UdpSocket::bind(&source).await?;
+        let mut sockets = Sockets::new(&source, options, socket_file.as_ref(), outbound_bind)?;
+        let replies = strategy.measures_replies();
+        let destinations: &[Endpoint] = if replies { &destinations } else { &[] };
+        // When the first packet without a reply was sent to each
+        // destination, and the pick of its selection.
+        let mut waiting: HashMap<Endpoint, (Instant, Pick)> = HashMap::new();
 
         info!("session started listening on {}", source);
         loop {
             let mut buf = [0; 1500];
-            let bytes = socket.recv(&mut buf).await?;
+            let packet = sockets.recv_from(&mut buf, destinations).await?;
+            // A packet without a reply for a while is taken as lost,
+            // so that the time waiting for it is not reported as the
+            // response time of a reply to a later packet.
+            waiting.retain(|destination, (sent, pick)| {
+                if sent.elapsed() < REPLY_TIMEOUT {
+                    return true;
+                }
+                debug!("No reply from {}", destination);
+                strategy.outcome(pick, destination, Outcome::Failed);
+                false
+            });
+            let (bytes, client) = match packet {
+                Packet::Client(bytes, client) => (bytes, client),
+                Packet::Reply(destination) => {
+                    debug!("Reply from {}", destination);
+                    if let Some((sent, pick)) = waiting.remove(&destination) {
+                        let outcome = Outcome::Replied(sent.elapsed());
+                        strategy.outcome(&pick, &destination, outcome);
+                    }
+                    continue;
+                }
+            };
             debug!("Receiving {} bytes", bytes);
             if bytes == 0 {
                 break;
             }
-            for addr in &strategy.destinations() {
+            let context = Context {
+                rule_id,
+                protocol: Protocol::Udp,
+                client: &client,
+                peeked: Some(&buf[0..bytes]),
+            };
+            let mut pick = Pick::default();
+            let selection = strategy.select(&context, &mut pick);
+            for addr in selection.destinations() {
+                throttle.wait(bytes).await;
                 debug!("Sending {} bytes to address {}", bytes, addr);
-                socket.send_to(&buf[0..bytes], &addr).await?;
+                let outcome = match sockets.send_to(&buf[0..bytes], addr).await {
+                    Ok(_) => {
+                        if replies {
+                            waiting
+                                .entry(addr.clone())
+                                .or_insert_with(|| (Instant::now(), pick.clone()));
+                        }
+                        Outcome::Sent
+                    }
+                    Err(err) => {
+                        warn!("unable to send to {}: {}", addr, err);
+                        Outcome::Failed
+                    }
+                };
+                strategy.outcome(&pick, addr, outcome);
             }
         }
         info!("session terminated");
         Ok(())
     }
 }
+
+/// Datagram socket of either family.
+enum Datagram {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+/// Source socket of a session together with the sockets used to send
+/// to destinations of the other family, which are created when they
+/// are first needed.
+struct Sockets {
+    source: Datagram,
+    options: SocketOptions,
+    bind: Option<OutboundBind>,
+    udp4: Option<UdpSocket>,
+    udp6: Option<UdpSocket>,
+    #[cfg(unix)]
+    unix: Option<UnixDatagram>,
+}
+
+impl Sockets {
+    fn new(
+        source: &Endpoint,
+        options: SocketOptions,
+        file: Option<&SocketFile>,
+        bind: Option<OutboundBind>,
+    ) -> io::Result<Self> {
+        let source = match source {
+            Endpoint::Inet(addr) => Datagram::Udp(socket::udp_socket(*addr, &options)?),
+            #[cfg(unix)]
+            endpoint => Datagram::Unix(socket::unix_datagram(Some(endpoint), file)?),
+            #[cfg(not(unix))]
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Unix domain sockets are not supported",
+                ))
+            }
+        };
+        Ok(Sockets {
+            source,
+            options,
+            bind,
+            udp4: None,
+            udp6: None,
+            #[cfg(unix)]
+            unix: None,
+        })
+    }
+
+    /// Receive a packet from a client or a reply from one of the
+    /// destinations, which can arrive on the source socket or on the
+    /// sockets used to send to destinations of the other family.
+    async fn recv_from(&self, buf: &mut [u8], destinations: &[Endpoint]) -> io::Result<Packet> {
+        // Only the sender of a reply is needed, so the contents are
+        // discarded.
+        let mut reply4 = [0; 1500];
+        let mut reply6 = [0; 1500];
+        let (bytes, sender) = tokio::select! {
+            result = self.recv_source(buf) => result?,
+            sender = recv_reply(self.udp4.as_ref(), &mut reply4, destinations) => {
+                return Ok(Packet::Reply(sender));
+            }
+            sender = recv_reply(self.udp6.as_ref(), &mut reply6, destinations) => {
+                return Ok(Packet::Reply(sender));
+            }
+        };
+        if destinations.contains(&sender) {
+            Ok(Packet::Reply(sender))
+        } else {
+            Ok(Packet::Client(bytes, sender))
+        }
+    }
+
+    /// Receive a packet on the source socket and return its size and
+    /// sender. Unix domain socket senders that did not bind have an
+    /// empty path.
+    async fn recv_source(&self, buf: &mut [u8]) -> io::Result<(usize, Endpoint)> {
+        match &self.source {
+            Datagram::Udp(socket) => {
+                let (bytes, addr) = socket.recv_from(buf).await?;
+                Ok((bytes, Endpoint::Inet(addr)))
+            }
+            #[cfg(unix)]
+            Datagram::Unix(socket) => {
+                let (bytes, addr) = socket.recv_from(buf).await?;
+                let path = addr.as_pathname().map(PathBuf::from).unwrap_or_default();
+                Ok((bytes, Endpoint::Unix(path)))
+            }
+        }
+    }
+
+    async fn send_to(&mut self, buf: &[u8], destination: &Endpoint) -> io::Result<usize> {
+        match (destination, &self.source) {
+            (Endpoint::Inet(addr), Datagram::Udp(socket)) if self.bind.is_none() => {
+                socket.send_to(buf, addr).await
+            }
+            (Endpoint::Inet(addr), _) => {
+                let slot = match addr {
+                    SocketAddr::V4(_) => &mut self.udp4,
+                    SocketAddr::V6(_) => &mut self.udp6,
+                };
+                if slot.is_none() {
+                    let socket = socket::udp_outbound(*addr, &self.options, self.bind.as_ref())?;
+                    *slot = Some(socket);
+                }
+                slot.as_ref().unwrap().send_to(buf, addr).await
+            }
+            #[cfg(unix)]
+            (endpoint, Datagram::Unix(socket)) => socket::unix_send_to(socket, buf, endpoint).await,
+            #[cfg(unix)]
+            (endpoint, _) => {
+                if self.unix.is_none() {
+                    self.unix = Some(socket::unix_datagram(None, None)?);
+                }
+                socket::unix_send_to(self.unix.as_ref().unwrap(), buf, endpoint).await
+            }
+            #[cfg(not(unix))]
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Unix domain sockets are not supported",
+            )),
+        }
+    }
+}
+
+/// Wait for a reply from a destination on a socket used to send to
+/// destinations, ignoring other packets and receive errors. Waits
+/// forever if the socket has not been created.
+async fn recv_reply(
+    socket: Option<&UdpSocket>,
+    buf: &mut [u8],
+    destinations: &[Endpoint],
+) -> Endpoint {
+    let socket = match socket {
+        Some(socket) => socket,
+        None => return futures::future::pending().await,
+    };
+    loop {
+        match socket.recv_from(buf).await {
+            Ok((_, addr)) if destinations.contains(&Endpoint::Inet(addr)) => {
+                return Endpoint::Inet(addr)
+            }
+            Ok((_, addr)) => debug!("Ignoring packet from {}", addr),
+            Err(err) => debug!("unable to receive reply: {}", err),
+        }
+    }
+}
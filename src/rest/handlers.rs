@@ -1,8 +1,11 @@
 //! Handlers for JSON requests
 
-use crate::{rest::DbRef, session::Rule};
+use crate::{
+    rest::DbRef,
+    session::{strategy::StrategyRegistry, Endpoint, RateLimits, Rule},
+};
 use serde::Serialize;
-use std::convert::Infallible;
+use std::{collections::BTreeMap, convert::Infallible};
 use warp::{self, http::StatusCode};
 
 #[derive(Serialize)]
@@ -10,13 +13,28 @@ struct CreateReply {
     rule_id: usize,
 }
 
+#[derive(Serialize)]
+struct ErrorReply {
+    error: String,
+}
+
 pub(crate) async fn list_rules(db: DbRef) -> Result<impl warp::Reply, Infallible> {
     let handle = db.read().await;
     let rules: Vec<&Rule> = handle.rules.iter().filter_map(|x| x.as_ref()).collect();
     Ok(warp::reply::json(&rules))
 }
 
+pub(crate) async fn list_strategies() -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::json(&StrategyRegistry::names()))
+}
+
 pub(crate) async fn create_rule(rule: Rule, db: DbRef) -> Result<impl warp::Reply, Infallible> {
+    if let Err(err) = rule.validate() {
+        let json = warp::reply::json(&ErrorReply {
+            error: err.to_string(),
+        });
+        return Ok(warp::reply::with_status(json, StatusCode::BAD_REQUEST));
+    }
     let mut handle = db.write().await;
     let id = handle.create_rule(rule);
     let json = warp::reply::json(&CreateReply { rule_id: id });
@@ -31,11 +49,146 @@ pub(crate) async fn delete_rule(rule_id: usize, db: DbRef) -> Result<impl warp::
     }
 }
 
+fn no_rule(rule_id: usize) -> warp::reply::WithStatus<warp::reply::Json> {
+    let json = warp::reply::json(&ErrorReply {
+        error: format!("no rule with id {}", rule_id),
+    });
+    warp::reply::with_status(json, StatusCode::NOT_FOUND)
+}
+
+pub(crate) async fn get_rate_limit(
+    rule_id: usize,
+    db: DbRef,
+) -> Result<impl warp::Reply, Infallible> {
+    let handle = db.read().await;
+    match handle.get_rule(rule_id) {
+        Some(rule) => {
+            let json = warp::reply::json(&rule.rate_limit.unwrap_or_default());
+            Ok(warp::reply::with_status(json, StatusCode::OK))
+        }
+        None => Ok(no_rule(rule_id)),
+    }
+}
+
+pub(crate) async fn set_rate_limit(
+    rule_id: usize,
+    limits: RateLimits,
+    db: DbRef,
+) -> Result<impl warp::Reply, Infallible> {
+    let mut handle = db.write().await;
+    let mut rule = match handle.get_rule(rule_id) {
+        Some(rule) => rule.clone(),
+        None => return Ok(no_rule(rule_id)),
+    };
+    rule.rate_limit = Some(limits);
+    if let Err(err) = rule.validate() {
+        let json = warp::reply::json(&ErrorReply {
+            error: err.to_string(),
+        });
+        return Ok(warp::reply::with_status(json, StatusCode::BAD_REQUEST));
+    }
+    handle.set_rate_limit(rule_id, Some(limits));
+    let json = warp::reply::json(&limits);
+    Ok(warp::reply::with_status(json, StatusCode::OK))
+}
+
+pub(crate) async fn get_weights(rule_id: usize, db: DbRef) -> Result<impl warp::Reply, Infallible> {
+    let handle = db.read().await;
+    match handle.get_rule(rule_id) {
+        Some(rule) => {
+            let json = warp::reply::json(&rule.weights);
+            Ok(warp::reply::with_status(json, StatusCode::OK))
+        }
+        None => Ok(no_rule(rule_id)),
+    }
+}
+
+pub(crate) async fn set_weights(
+    rule_id: usize,
+    weights: BTreeMap<Endpoint, u32>,
+    db: DbRef,
+) -> Result<impl warp::Reply, Infallible> {
+    let mut handle = db.write().await;
+    let mut rule = match handle.get_rule(rule_id) {
+        Some(rule) => rule.clone(),
+        None => return Ok(no_rule(rule_id)),
+    };
+    rule.weights = weights.clone();
+    if let Err(err) = rule.validate() {
+        let json = warp::reply::json(&ErrorReply {
+            error: err.to_string(),
+        });
+        return Ok(warp::reply::with_status(json, StatusCode::BAD_REQUEST));
+    }
+    let json = warp::reply::json(&weights);
+    handle.set_weights(rule_id, weights);
+    Ok(warp::reply::with_status(json, StatusCode::OK))
+}
+
+pub(crate) async fn get_latencies(
+    rule_id: usize,
+    db: DbRef,
+) -> Result<impl warp::Reply, Infallible> {
+    let handle = db.read().await;
+    match handle.get_latencies(rule_id) {
+        Some(latencies) => {
+            let millis: BTreeMap<Endpoint, f64> = latencies
+                .get()
+                .into_iter()
+                .map(|(destination, latency)| (destination, latency.as_secs_f64() * 1000.0))
+                .collect();
+            let json = warp::reply::json(&millis);
+            Ok(warp::reply::with_status(json, StatusCode::OK))
+        }
+        None => Ok(no_rule(rule_id)),
+    }
+}
+
+pub(crate) async fn list_connections(
+    rule_id: usize,
+    db: DbRef,
+) -> Result<impl warp::Reply, Infallible> {
+    let handle = db.read().await;
+    match handle.get_registry(rule_id) {
+        Some(registry) => {
+            let json = warp::reply::json(&registry.list());
+            Ok(warp::reply::with_status(json, StatusCode::OK))
+        }
+        None => Ok(no_rule(rule_id)),
+    }
+}
+
+pub(crate) async fn kill_connection(
+    rule_id: usize,
+    conn_id: u64,
+    db: DbRef,
+) -> Result<impl warp::Reply, Infallible> {
+    let handle = db.read().await;
+    match handle.get_registry(rule_id) {
+        Some(registry) if registry.kill(conn_id) => Ok(StatusCode::NO_CONTENT),
+        _ => Ok(StatusCode::NOT_FOUND),
+    }
+}
+
+pub(crate) async fn rule_stats(rule_id: usize, db: DbRef) -> Result<impl warp::Reply, Infallible> {
+    let handle = db.read().await;
+    match handle.get_stats(rule_id) {
+        Some(stats) => {
+            let json = warp::reply::json(&stats.report());
+            Ok(warp::reply::with_status(json, StatusCode::OK))
+        }
+        None => Ok(no_rule(rule_id)),
+    }
+}
+
 pub(crate) async fn update_rule(
     rule_id: usize,
     rule: Rule,
     db: DbRef,
 ) -> Result<impl warp::Reply, Infallible> {
+    if rule.validate().is_err() {
+        return Ok(StatusCode::BAD_REQUEST);
+    }
     let mut handle = db.write().await;
     match handle.update_rule(rule_id, rule) {
         Some(_) => Ok(StatusCode::OK),
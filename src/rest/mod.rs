@@ -1,8 +1,21 @@
 //! Module that handles JSON requests.
 //!
-//! JSON structure contains the following fields:
-//!
+//! The following resources are available:
 //!
+//! - `GET /rules` lists all rules.
+//! - `GET /strategies` lists the names of the registered strategies.
+//! - `POST /rules` creates a new rule.
+//! - `PUT /rules/{id}` updates a rule.
+//! - `DELETE /rules/{id}` deletes a rule.
+//! - `GET /rules/{id}/stats` returns the counters for a rule.
+//! - `GET /rules/{id}/rate_limit` returns the rate limits for a rule.
+//! - `PUT /rules/{id}/rate_limit` changes the rate limits for a rule.
+//! - `GET /rules/{id}/weights` returns the destination weights for a rule.
+//! - `PUT /rules/{id}/weights` changes the destination weights for a rule.
+//! - `GET /rules/{id}/latencies` returns the smoothed destination
+//!   latencies for a rule, in milliseconds.
+//! - `GET /rules/{id}/connections` lists the active connections of a rule.
+//! - `DELETE /rules/{id}/connections/{conn_id}` closes a connection.
 
 mod handlers;
 mod resources;
@@ -19,7 +32,16 @@ fn with_db(db: DbRef) -> impl Filter<Extract = (DbRef,), Error = Infallible> + C
 fn resources(
     db: DbRef,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    resources::list_rules(db.clone())
+    resources::rule_stats(db.clone())
+        .or(resources::get_rate_limit(db.clone()))
+        .or(resources::set_rate_limit(db.clone()))
+        .or(resources::get_weights(db.clone()))
+        .or(resources::set_weights(db.clone()))
+        .or(resources::get_latencies(db.clone()))
+        .or(resources::list_connections(db.clone()))
+        .or(resources::kill_connection(db.clone()))
+        .or(resources::list_strategies())
+        .or(resources::list_rules(db.clone()))
         .or(resources::update_rule(db.clone()))
         .or(resources::create_rule(db.clone()))
         .or(resources::delete_rule(db))
@@ -2,8 +2,9 @@
 
 use crate::{
     rest::{handlers, with_db},
-    session::{DbRef, Rule},
+    session::DbRef,
 };
+use serde::de::DeserializeOwned;
 use warp::Filter;
 
 /// List all available rules.
@@ -18,6 +19,14 @@ pub(crate) fn list_rules(
         .and_then(handlers::list_rules)
 }
 
+/// List the names of the registered strategies.
+pub(crate) fn list_strategies(
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("strategies")
+        .and(warp::get())
+        .and_then(handlers::list_strategies)
+}
+
 pub(crate) fn create_rule(
     db: DbRef,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
@@ -47,6 +56,91 @@ pub(crate) fn update_rule(
         .and_then(handlers::update_rule)
 }
 
-fn json_body() -> impl Filter<Extract = (Rule,), Error = warp::Rejection> + Clone {
+/// Get the statistics for a rule.
+///
+/// Returns a JSON object with the counters for the rule.
+pub(crate) fn rule_stats(
+    db: DbRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("rules" / usize / "stats")
+        .and(warp::get())
+        .and(with_db(db))
+        .and_then(handlers::rule_stats)
+}
+
+/// Get the rate limits for a rule.
+pub(crate) fn get_rate_limit(
+    db: DbRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("rules" / usize / "rate_limit")
+        .and(warp::get())
+        .and(with_db(db))
+        .and_then(handlers::get_rate_limit)
+}
+
+/// Change the rate limits for a rule without restarting it.
+pub(crate) fn set_rate_limit(
+    db: DbRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("rules" / usize / "rate_limit")
+        .and(warp::put())
+        .and(json_body())
+        .and(with_db(db))
+        .and_then(handlers::set_rate_limit)
+}
+
+/// Get the destination weights for a rule.
+pub(crate) fn get_weights(
+    db: DbRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("rules" / usize / "weights")
+        .and(warp::get())
+        .and(with_db(db))
+        .and_then(handlers::get_weights)
+}
+
+/// Change the destination weights for a rule without restarting it.
+pub(crate) fn set_weights(
+    db: DbRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("rules" / usize / "weights")
+        .and(warp::put())
+        .and(json_body())
+        .and(with_db(db))
+        .and_then(handlers::set_weights)
+}
+
+/// Get the smoothed destination latencies for a rule.
+pub(crate) fn get_latencies(
+    db: DbRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("rules" / usize / "latencies")
+        .and(warp::get())
+        .and(with_db(db))
+        .and_then(handlers::get_latencies)
+}
+
+/// List the active connections of a rule.
+pub(crate) fn list_connections(
+    db: DbRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("rules" / usize / "connections")
+        .and(warp::get())
+        .and(with_db(db))
+        .and_then(handlers::list_connections)
+}
+
+/// Close an active connection of a rule.
+pub(crate) fn kill_connection(
+    db: DbRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("rules" / usize / "connections" / u64)
+        .and(warp::delete())
+        .and(with_db(db))
+        .and_then(handlers::kill_connection)
+}
+
+fn json_body<T: DeserializeOwned + Send>(
+) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
     warp::body::content_length_limit(1024 * 16).and(warp::body::json())
 }
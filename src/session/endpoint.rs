@@ -0,0 +1,142 @@
+//! Endpoints that rules listen on and forward to.
+//!
+//! An endpoint is either an IP socket address, written as usual, or a
+//! Unix domain socket, written as `unix:` followed by the path of the
+//! socket file. Sockets in the Linux abstract namespace are written
+//! as `unix:@` followed by the name.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
+
+/// Prefix for Unix domain socket endpoints.
+const UNIX_PREFIX: &str = "unix:";
+
+/// Address of a socket.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Endpoint {
+    /// IP socket address.
+    Inet(SocketAddr),
+    /// Unix domain socket bound to a file. The path is empty for
+    /// unnamed sockets, such as clients that did not bind.
+    Unix(PathBuf),
+    /// Unix domain socket in the abstract namespace.
+    Abstract(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError(String);
+
+impl Endpoint {
+    /// Get the IP socket address, if this is an IP endpoint.
+    pub fn inet(&self) -> Option<SocketAddr> {
+        match self {
+            Endpoint::Inet(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+
+    /// Get the IP address, if this is an IP endpoint.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.inet().map(|addr| addr.ip())
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.inet().is_some_and(|addr| addr.is_ipv6())
+    }
+
+    pub fn is_unix(&self) -> bool {
+        !matches!(self, Endpoint::Inet(_))
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Endpoint {
+        Endpoint::Inet(addr)
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = ParseError;
+
+    fn from_str(text: &str) -> Result<Endpoint, ParseError> {
+        match text.strip_prefix(UNIX_PREFIX) {
+            Some(name) if name.len() > 1 && name.starts_with('@') => {
+                Ok(Endpoint::Abstract(name[1..].to_string()))
+            }
+            Some(path) if path.starts_with('/') => Ok(Endpoint::Unix(PathBuf::from(path))),
+            Some(_) => Err(ParseError(format!(
+                "'{}' is not an absolute socket path or abstract name",
+                text
+            ))),
+            None => text
+                .parse()
+                .map(Endpoint::Inet)
+                .map_err(|_| ParseError(format!("'{}' is not a socket address", text))),
+        }
+    }
+}
+
+impl TryFrom<String> for Endpoint {
+    type Error = ParseError;
+
+    fn try_from(text: String) -> Result<Endpoint, ParseError> {
+        text.parse()
+    }
+}
+
+impl From<Endpoint> for String {
+    fn from(endpoint: Endpoint) -> String {
+        endpoint.to_string()
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Inet(addr) => write!(f, "{}", addr),
+            Endpoint::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+            Endpoint::Abstract(name) => write!(f, "{}@{}", UNIX_PREFIX, name),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let cases = [
+            (
+                "127.0.0.1:80",
+                Endpoint::Inet("127.0.0.1:80".parse().unwrap()),
+            ),
+            ("[::1]:80", Endpoint::Inet("[::1]:80".parse().unwrap())),
+            (
+                "unix:/var/run/docker.sock",
+                Endpoint::Unix(PathBuf::from("/var/run/docker.sock")),
+            ),
+            ("unix:@router", Endpoint::Abstract("router".to_string())),
+        ];
+        for (text, endpoint) in &cases {
+            assert_eq!(text.parse::<Endpoint>().as_ref(), Ok(endpoint));
+            assert_eq!(&endpoint.to_string(), text);
+        }
+        for text in &["unix:relative.sock", "unix:@", "localhost:80"] {
+            assert!(text.parse::<Endpoint>().is_err(), "{}", text);
+        }
+    }
+}
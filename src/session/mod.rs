@@ -1,10 +1,22 @@
+pub mod endpoint;
+pub mod registry;
 pub mod rules;
+pub mod script;
+pub mod stats;
 pub mod strategy;
 
-use crate::{protocol, protocol::udp::UdpSession, rest, session::strategy::StrategyFactory};
+use crate::{
+    protocol,
+    protocol::{tcp::TcpSession, udp::UdpSession},
+    rest,
+    session::strategy::StrategyFactory,
+};
 use async_trait::async_trait;
+pub use endpoint::Endpoint;
 use futures::{stream::FuturesUnordered, StreamExt};
-pub use rules::{Database, Mode, Protocol, Route, Rule};
+pub use rules::{
+    Database, ListenerTls, Mode, Protocol, RateLimit, RateLimits, Route, Rule, UpstreamTls,
+};
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::Arc,
@@ -72,12 +84,29 @@ impl Manager {
     /// for the rule and add it to the set of tasks running as well as
     /// updating the database with all rules.
     pub async fn add_rule(&mut self, rule: Rule) {
-        let session = tokio::spawn({
-            let strategy = StrategyFactory::make(&rule);
-            UdpSession::new(&rule, strategy).await.start()
-        });
+        let (id, stats, throttle, registry, tables) = {
+            let mut database = self.database.write().await;
+            let id = database.create_rule(rule.clone());
+            (
+                id,
+                database.get_stats(id).unwrap(),
+                database.get_throttle(id).unwrap(),
+                database.get_registry(id).unwrap(),
+                database.get_tables(id).unwrap(),
+            )
+        };
+        let strategy = StrategyFactory::make(&rule, &tables);
+        let session = match rule.protocol {
+            Protocol::Udp => {
+                tokio::spawn(UdpSession::new(id, &rule, strategy, throttle).await.start())
+            }
+            Protocol::Tcp => tokio::spawn(
+                TcpSession::new(id, rule, strategy, stats, throttle, registry, tables)
+                    .await
+                    .start(),
+            ),
+        };
         self.sessions.push(session);
-        self.database.write().await.create_rule(rule);
     }
 
     /// Start the manager by starting all tasks.
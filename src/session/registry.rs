@@ -0,0 +1,186 @@
+//! Registry of active connections.
+//!
+//! Each rule has a registry with the connections that are currently
+//! forwarded. The registry records the client, the chosen destination,
+//! the start time, and the number of bytes transferred in each
+//! direction, and connections can be closed through the registry.
+
+use crate::session::Endpoint;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Notify;
+
+/// Active connections of a rule.
+#[derive(Default)]
+pub struct Registry {
+    next_id: AtomicU64,
+    connections: Mutex<BTreeMap<u64, Arc<Connection>>>,
+}
+
+/// Active connection.
+pub struct Connection {
+    id: u64,
+    client: Endpoint,
+    backend: Mutex<Option<Endpoint>>,
+    started: SystemTime,
+    /// Number of bytes sent from the client to the destination.
+    pub bytes_to_backend: AtomicU64,
+    /// Number of bytes sent from the destination to the client.
+    pub bytes_to_client: AtomicU64,
+    kill: Notify,
+}
+
+/// Description of an active connection.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub client: Endpoint,
+    pub backend: Option<Endpoint>,
+    /// Start time in milliseconds since the Unix epoch.
+    pub started_at_ms: u64,
+    pub bytes_to_backend: u64,
+    pub bytes_to_client: u64,
+}
+
+/// Registration of a connection. The connection is removed from the
+/// registry when the registration is dropped.
+pub struct Registration {
+    registry: Arc<Registry>,
+    connection: Arc<Connection>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Default::default()
+    }
+
+    /// Register a new connection from a client.
+    pub fn register(self: &Arc<Self>, client: Endpoint) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let connection = Arc::new(Connection {
+            id,
+            client,
+            backend: Mutex::new(None),
+            started: SystemTime::now(),
+            bytes_to_backend: AtomicU64::new(0),
+            bytes_to_client: AtomicU64::new(0),
+            kill: Notify::new(),
+        });
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(id, connection.clone());
+        Registration {
+            registry: self.clone(),
+            connection,
+        }
+    }
+
+    /// Describe all active connections.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let connections = self.connections.lock().unwrap();
+        connections
+            .values()
+            .map(|connection| connection.info())
+            .collect()
+    }
+
+    /// Close a connection. Returns false if there is no such
+    /// connection.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.connections.lock().unwrap().get(&id) {
+            Some(connection) => {
+                connection.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Connection {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn client(&self) -> &Endpoint {
+        &self.client
+    }
+
+    /// Record the destination that the connection is forwarded to.
+    pub fn set_backend(&self, backend: Endpoint) {
+        *self.backend.lock().unwrap() = Some(backend);
+    }
+
+    /// Wait until the connection is killed.
+    pub async fn killed(&self) {
+        self.kill.notified().await
+    }
+
+    fn info(&self) -> ConnectionInfo {
+        let started = self.started.duration_since(UNIX_EPOCH).unwrap_or_default();
+        ConnectionInfo {
+            id: self.id,
+            client: self.client.clone(),
+            backend: self.backend.lock().unwrap().clone(),
+            started_at_ms: started.as_millis() as u64,
+            bytes_to_backend: self.bytes_to_backend.load(Ordering::Relaxed),
+            bytes_to_client: self.bytes_to_client.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Deref for Registration {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut connections = self.registry.connections.lock().unwrap();
+        connections.remove(&self.connection.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_registry() {
+        let registry = Arc::new(Registry::new());
+        let first = registry.register("10.0.0.1:4711".parse().unwrap());
+        let second = registry.register("10.0.0.2:4711".parse().unwrap());
+        first.set_backend("10.0.1.1:80".parse().unwrap());
+        first.bytes_to_backend.fetch_add(100, Ordering::Relaxed);
+
+        let list = registry.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].backend, Some("10.0.1.1:80".parse().unwrap()));
+        assert_eq!(list[0].bytes_to_backend, 100);
+        assert_eq!(list[1].backend, None);
+
+        // Killing a connection wakes up the connection, even if it is
+        // not waiting yet.
+        assert!(registry.kill(second.id()));
+        assert!(!registry.kill(17));
+        tokio::time::timeout(Duration::from_secs(1), second.killed())
+            .await
+            .unwrap();
+
+        drop(first);
+        assert_eq!(registry.list().len(), 1);
+    }
+}
@@ -18,6 +18,13 @@
 //!
 //! - One or more destination addresses to forward to.
 //!
+//! Sources and destinations are either IP socket addresses or Unix
+//! domain sockets, written as `unix:/path/to/socket` for sockets bound
+//! to a file and `unix:@name` for sockets in the Linux abstract
+//! namespace. Stream sockets are used for TCP rules and datagram
+//! sockets for UDP rules, so a rule can bridge between the two
+//! families.
+//!
 //! # Broadcast Mode
 //!
 //! In broadcast mode, each packet received on a source address is
@@ -34,9 +41,296 @@
 //! For UDP, the packets are sent to the destination ports in a
 //! round-robin fashion.
 //!
+//! # Weighted Round-Robin Mode
+//!
+//! In weighted round-robin mode, connections or packets are sent to
+//! the destinations in proportion to the `weights` field, which maps
+//! destinations to weights. Destinations without a weight have weight
+//! 1 and destinations with weight 0 are not used. The picks are spread
+//! out, so with weights 5, 1, and 1, the first destination is never
+//! picked more than twice in a row. The weights can be changed while
+//! the rule is running.
+//!
+//! # Least-Connections Mode
+//!
+//! In least-connections mode, a TCP connection is established to the
+//! destination with the fewest active connections. When
+//! several destinations have equally few connections, they take turns.
+//! This mode is only valid for TCP, and works better than round-robin
+//! when connections vary a lot in how long they live.
+//!
+//! # Consistent-Hash Mode
+//!
+//! In consistent-hash mode, each client is sent to a destination
+//! picked by hashing the client address, so a client keeps using the
+//! same destination, which is useful for caches. The hashing is
+//! consistent: adding or removing a destination only moves the
+//! clients of a small share of the hash ring. The `consistent_hash`
+//! field sets the `key`, which is `"ip"` to hash the IP address of
+//! the client, the default, or `"address"` to hash the port as well,
+//! and the number of `virtual_nodes` for each destination on the ring.
+//! Unix domain socket clients are hashed by path, and clients that
+//! did not bind all hash to the same destination.
+//!
+//! # Random and Power-of-Two-Choices Modes
+//!
+//! In random mode, each connection or packet is sent to a destination
+//! picked at random. In power-of-two-choices mode, `"p2c"`, two
+//! destinations are picked at random and the one with the fewest
+//! connections in flight is used, or the one with the lowest recent
+//! connect time if both have equally many. When several routers share
+//! the same destinations, this spreads the load without all routers
+//! sending to the least loaded destination at once. The `seed` field
+//! makes the random choices repeatable, which is useful for tests.
+//!
+//! # Peak-EWMA Mode
+//!
+//! In peak-EWMA mode, a TCP connection is established to the
+//! destination with the lowest smoothed connect time, multiplied by
+//! the number of connections in flight to it plus one. The smoothed
+//! connect time is an exponentially weighted moving average that jumps
+//! up at once when a connection is slower, and decays over the
+//! `decay_ms` field of the `peak_ewma` settings, 10 seconds by default.
+//! A failed connection counts as taking at least the penalty time,
+//! without ever lowering the smoothed time, and destinations without
+//! a connect time are assumed to take the `penalty_ms` time, 100
+//! milliseconds by default. The smoothed connect times can be read
+//! through the REST API.
+//!
+//! For UDP, each packet is sent to the destination with the lowest
+//! smoothed response time, which is the time from the first packet
+//! sent to the destination until a packet comes back from it. A
+//! destination that has not replied yet is assumed to take at least
+//! the time spent waiting, and one that has not replied within two
+//! seconds is taken as failed. Packets from destinations are only
+//! treated as replies, and not forwarded, in UDP rules that measure
+//! response times, which are those using peak-EWMA or `p2c` mode.
+//!
+//! # Sticky Sessions
+//!
+//! The `sticky` field makes a rule send each client to the same
+//! destination, which is first picked using the mode of the rule.
+//! Clients are identified by IP address by default, or by address and
+//! port if the `key` is `"address"`. A client is sent to a new
+//! destination after it has been idle for `ttl_ms` milliseconds, five
+//! minutes by default, or when a connection to, or a packet sent to,
+//! its destination fails. At most `max_clients` clients are
+//! remembered, 10000 by default, and when the table is full idle
+//! clients are forgotten first, and otherwise the client seen least
+//! recently. Sticky sessions only apply to the rule destinations, not
+//! to the pools picked by server name, client identity, or sniffing.
+//!
+//! # Script Mode
+//!
+//! In script mode, the destinations are picked by a Rhai script in the
+//! file given by the `path` of the `script` field. The script is run
+//! for each connection or packet with the `client`, `client_ip`,
+//! `protocol`, `time`, and `destinations` variables set, and selects
+//! one destination with a string or an index into `destinations`,
+//! several with an array of strings, or none with `()`. The script
+//! cannot read files or import modules and can run at most
+//! `max_operations` operations, 100000 by default. The script file is
+//! reloaded when it changes. If the script fails, the destinations are
+//! used in round-robin fashion instead.
+//!
+//! # Source Routing
+//!
+//! The `source_routes` field sends clients to different destinations
+//! depending on their address. Each route has a `network`, such as
+//! `"10.0.0.0/8"`, an optional `port_range` with the `first` and
+//! `last` client port, and the `destinations` for the clients that
+//! match, together with an optional `mode` for them. The first route
+//! matching a client is used, and clients matching no route, as well
+//! as Unix domain socket clients, use the rule destinations. Routes
+//! without a mode use the mode of the rule. For TCP, server name,
+//! client identity, and sniffing routes are used before source
+//! routes.
+//!
+//! # Named Strategies
+//!
+//! Programs using the router as a library can register their own
+//! strategies by name in the strategy registry. A rule uses such a
+//! strategy with the `strategy` field, which has the `name` of the
+//! strategy and optional `params` that are passed to the strategy as
+//! JSON. The mode of the rule is then only used if the strategy cannot
+//! be created. Rules naming a strategy that is not registered, or with
+//! parameters the strategy does not accept, are rejected.
+//!
+//! # Backup Destinations
+//!
+//! The `backups` field gives `tiers` of destinations that are only
+//! used when every destination of the tiers before them is down. The
+//! rule destinations form the first tier. A destination is down after
+//! a connection to it has failed, and is tried again after `retry_ms`
+//! milliseconds. Each tier is used with
+//! the mode of the rule, and the rule switches back to a higher tier
+//! as soon as one of its destinations works again. If all tiers are
+//! down, the rule destinations are used. Backups only apply to the
+//! rule destinations, not to the pools picked by server name, client
+//! identity, or sniffing. Sending a packet does not show that a
+//! destination is up, so backups are only supported for TCP rules.
+//!
+//! # Hostname Routing
+//!
+//! TCP rules can pick the destinations based on the Server Name
+//! Indication (SNI) in the TLS ClientHello sent by the client. The
+//! `sni` field maps hostname patterns to destination pools, where a
+//! pattern is either a hostname or a wildcard pattern like
+//! `*.example.com`. Connections that do not match any pattern, that
+//! do not carry a server name, or that are not TLS at all, use the
+//! rule destinations. The TLS stream is forwarded untouched.
+//!
+//! # Upstream TLS
+//!
+//! TCP rules can wrap the outbound connections in TLS using the
+//! `upstream_tls` field, which allows plaintext clients to reach
+//! destinations that only accept TLS. The destination certificate is
+//! verified against the CA certificates in `ca_file` using
+//! `server_name` as the expected name, which is also sent as SNI. If
+//! `cert_file` and `key_file` are given, the certificate is presented
+//! to destinations that require client authentication.
+//!
+//! # TLS Termination
+//!
+//! TCP rules can terminate TLS from clients using the `tls` field,
+//! which holds the certificate and key the router presents. If
+//! `client_ca_file` is given, clients have to present a certificate
+//! signed by one of the CA certificates in the file. The identity of
+//! the client is the subject common name together with the DNS, email
+//! and URI subject alternative names of the certificate. If
+//! `client_names` is not empty, at least one of the names in the
+//! identity has to match one of the patterns, and `client_pools` can
+//! be used to pin clients to destination pools based on their
+//! identity. When TLS is terminated, the `sni` map is matched against
+//! the server name the client sent in the handshake.
+//!
+//! Rejected handshakes are counted for each reason in the rule
+//! statistics.
+//!
+//! # Connection Limits
+//!
+//! TCP rules can limit the number of active connections using
+//! `max_connections` for the rule as a whole and
+//! `max_connections_per_client_ip` for each client address. What
+//! happens with connections over the limit is decided by
+//! `over_limit`: they are either closed immediately (`"close"`, the
+//! default) or queued until a connection closes, for at most the
+//! given time (`{"queue": {"timeout_ms": 500}}`).
+//!
+//! # Connection Timeouts
+//!
+//! TCP rules can close connections that have not transferred any data
+//! in either direction for `idle_timeout_ms` milliseconds, and
+//! connections that have been open for more than `max_lifetime_ms`
+//! milliseconds. Both sides of the connection are shut down and the
+//! reason for closing connections is counted in the rule statistics.
+//!
+//! # Rate Limits
+//!
+//! The `rate_limit` field limits the bandwidth used by a rule using
+//! token buckets. The `per_rule` limit applies to all traffic of the
+//! rule and the `per_connection` limit applies to each TCP connection
+//! separately. Each limit is given as `bytes_per_second` together with
+//! a `burst` size in bytes, and applies to both directions together.
+//! UDP rules only support the `per_rule` limit. The limits can be
+//! changed through the REST API without dropping connections.
+//!
+//! # Zero-Copy Forwarding
+//!
+//! If `zero_copy` is set for a TCP rule, data is moved between the
+//! client and the destination using `splice(2)` on Linux, without
+//! copying it through userspace buffers. This only applies to plain
+//! TCP connections, so it cannot be combined with TLS termination or
+//! upstream TLS. On other platforms, data is copied as usual.
+//!
+//! # Warm Connection Pools
+//!
+//! The `warm_pool` field makes a TCP rule keep `min_idle` connections
+//! open to each destination, which are handed to new clients instead
+//! of connecting when the client arrives. Connections are replaced in
+//! the background when they are used or are older than `max_age_ms`.
+//! Since the destination does not know which client it will talk to,
+//! this is only safe for protocols where the server does not speak
+//! first. Connections where the destination has already sent data are
+//! discarded, unless `server_first_safe` is set.
+//!
+//! # Socket Options
+//!
+//! The `socket_options` field sets options on the listening socket,
+//! on accepted connections, and on outbound sockets of a rule:
+//!
+//! - `nodelay` sets `TCP_NODELAY` and is only valid for TCP.
+//!
+//! - `keepalive` enables TCP keepalive probes after `idle_ms`, with
+//!   the optional `interval_ms` between probes and `count` probes
+//!   before the connection is dropped. It is only valid for TCP.
+//!
+//! - `recv_buffer_size` and `send_buffer_size` set `SO_RCVBUF` and
+//!   `SO_SNDBUF`.
+//!
+//! - `tos` sets the type of service byte, or the traffic class for
+//!   IPv6, while `dscp` sets only the differentiated services code
+//!   point of it. At most one of them can be given.
+//!
+//! - `v6only` sets `IPV6_V6ONLY` and is only valid for IPv6 sources.
+//!
+//! # Outbound Bind Address
+//!
+//! The `outbound_bind` field sets the local `address` used for
+//! connections to destinations and for packets sent to destinations,
+//! which allows firewalls to identify traffic from a rule on hosts
+//! with several addresses. With a `port_range`, the local port is
+//! picked from the range, which is given by its `first` and `last`
+//! port. UDP rules send from a separate socket bound to the address
+//! instead of the source socket. The address has to be of the same
+//! family as the IP destinations.
+//!
+//! # Unix Domain Sockets
+//!
+//! A socket file left behind for a Unix domain socket source is
+//! removed before the source is bound. The `socket_file` field sets the
+//! permissions of the created socket file using an octal `mode`, and
+//! the `owner` and `group` using names or numeric ids. Socket options
+//! only apply to IP sockets, and warm connection pools and zero-copy
+//! forwarding only apply to TCP connections.
+//!
+//! # Protocol Sniffing
+//!
+//! The `sniff` field allows a TCP rule to serve several protocols on
+//! the same port. The first bytes sent by the client are matched
+//! against a list of `routes`, which are tried in order, and the
+//! connection is forwarded to the destinations of the first route
+//! that matches. A route can match a TLS handshake (`"tls"`), an HTTP
+//! request (`"http"`), an SSH identification string (`"ssh"`), or a
+//! regular expression on the first bytes (`{"regex": "..."}`). The
+//! `"timeout"` route matches clients that send nothing for
+//! `timeout_ms` milliseconds, which is used for protocols where the
+//! server speaks first. Connections that do not match any route use
+//! the rule destinations. The bytes read are replayed to the
+//! destination.
+//!
+//! # Shadow Traffic
+//!
+//! The `shadow` field makes a TCP rule copy the data sent by each
+//! client to a second `destination`, for example to test a new version
+//! of a service with production traffic. Responses from the shadow
+//! are discarded. The shadow never slows down the real connection: if
+//! more than `max_buffer` bytes are waiting to be sent to the shadow,
+//! or the shadow fails, only the shadow connection is dropped.
+//!
 
+use crate::{
+    protocol::throttle::Throttle,
+    session::{
+        endpoint::Endpoint,
+        registry::Registry,
+        stats::RuleStats,
+        strategy::{Latencies, Tables, Weights},
+    },
+};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
-use std::{mem, net::SocketAddr};
+use std::{collections::BTreeMap, net::IpAddr, path::PathBuf, sync::Arc};
 
 /// Rule describing where to listen for connections or packets and
 /// where to forward the connections or packets.
@@ -44,14 +338,382 @@ use std::{mem, net::SocketAddr};
 pub struct Rule {
     pub protocol: Protocol,
     pub mode: Mode,
-    pub source: SocketAddr,
-    pub destinations: Vec<SocketAddr>,
+    pub source: Endpoint,
+    pub destinations: Vec<Endpoint>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sni: BTreeMap<String, Vec<Endpoint>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_tls: Option<UpstreamTls>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<ListenerTls>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections_per_client_ip: Option<usize>,
+    #[serde(default, skip_serializing_if = "OverLimit::is_default")]
+    pub over_limit: OverLimit,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_lifetime_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimits>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub zero_copy: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warm_pool: Option<WarmPool>,
+    #[serde(default, skip_serializing_if = "SocketOptions::is_default")]
+    pub socket_options: SocketOptions,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shadow: Option<Shadow>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sniff: Option<Sniff>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket_file: Option<SocketFile>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outbound_bind: Option<OutboundBind>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backups: Option<Backups>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub weights: BTreeMap<Endpoint, u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consistent_hash: Option<ConsistentHash>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peak_ewma: Option<PeakEwma>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sticky: Option<Sticky>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<Script>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source_routes: Vec<SourceRoute>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strategy: Option<NamedStrategy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+/// What to do with connections over the connection limits.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverLimit {
+    /// Close the connection immediately.
+    #[default]
+    Close,
+    /// Wait for a connection to close, but at most the timeout.
+    Queue { timeout_ms: u64 },
+}
+
+impl OverLimit {
+    fn is_default(&self) -> bool {
+        *self == OverLimit::default()
+    }
+}
+
+/// Options for the sockets of a rule.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct SocketOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodelay: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive: Option<Keepalive>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recv_buffer_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send_buffer_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tos: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dscp: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v6only: Option<bool>,
+}
+
+impl SocketOptions {
+    pub fn is_default(&self) -> bool {
+        *self == SocketOptions::default()
+    }
+
+    /// Type of service byte to set, if any.
+    pub fn traffic_class(&self) -> Option<u8> {
+        self.tos.or_else(|| self.dscp.map(|dscp| dscp << 2))
+    }
+}
+
+/// Permissions and ownership of the socket file created for a Unix
+/// domain socket source.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct SocketFile {
+    /// File mode as an octal number, for example `"0660"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    /// User name or numeric user id of the owner.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Group name or numeric group id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+impl SocketFile {
+    /// Parse the file mode, if there is one.
+    pub fn parse_mode(&self) -> Result<Option<u32>, String> {
+        match &self.mode {
+            Some(mode) => match u32::from_str_radix(mode, 8) {
+                Ok(bits) if bits <= 0o7777 => Ok(Some(bits)),
+                _ => Err(format!("'{}' is not an octal file mode", mode)),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+/// Local address for outbound connections and packets.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct OutboundBind {
+    pub address: IpAddr,
+    /// Local ports to use. The kernel picks a port if there is no
+    /// range.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port_range: Option<PortRange>,
+}
+
+/// Inclusive range of ports.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PortRange {
+    /// Check if a port is in the range.
+    pub fn contains(&self, port: u16) -> bool {
+        self.first <= port && port <= self.last
+    }
+}
+
+/// Destinations for clients from a network and, optionally, a range
+/// of client ports.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SourceRoute {
+    pub network: IpNet,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port_range: Option<PortRange>,
+    pub destinations: Vec<Endpoint>,
+    /// Mode used for the destinations, which is the mode of the rule
+    /// if not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<Mode>,
+}
+
+/// TCP keepalive settings.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct Keepalive {
+    /// Idle time before the first probe is sent.
+    pub idle_ms: u64,
+    /// Time between probes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval_ms: Option<u64>,
+    /// Number of unanswered probes before the connection is dropped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<u32>,
+}
+
+/// Settings for pools of warm connections to destinations.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct WarmPool {
+    /// Number of idle connections to keep for each destination.
+    pub min_idle: usize,
+    /// Maximum time an idle connection is kept.
+    pub max_age_ms: u64,
+    /// Connections where the destination already sent data can be
+    /// handed out.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub server_first_safe: bool,
+}
+
+/// Settings for mirroring client traffic to a shadow destination.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Shadow {
+    pub destination: Endpoint,
+    /// Number of bytes that can wait to be sent to the shadow before
+    /// it is dropped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_buffer: Option<usize>,
+}
+
+/// Strategy registered by name, with parameters for its constructor.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct NamedStrategy {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub params: serde_json::Value,
+}
+
+/// Settings for consistent hashing of clients.
+#[derive(Debug, Default, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct ConsistentHash {
+    #[serde(default, skip_serializing_if = "HashKey::is_default")]
+    pub key: HashKey,
+    /// Number of points on the hash ring for each destination.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub virtual_nodes: Option<usize>,
+}
+
+/// Settings for picking destinations by their connect or response time.
+#[derive(Debug, Default, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct PeakEwma {
+    /// Time over which the smoothed time decays.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decay_ms: Option<u64>,
+    /// Time assumed for destinations that have none yet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub penalty_ms: Option<u64>,
+}
+
+/// Script picking the destinations of a rule.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Script {
+    pub path: PathBuf,
+    /// Number of operations the script can run for each selection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_operations: Option<u64>,
+}
+
+/// Settings for sending clients to the same destination.
+#[derive(Debug, Default, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct Sticky {
+    #[serde(default, skip_serializing_if = "HashKey::is_default")]
+    pub key: HashKey,
+    /// Time a client can be idle before it is sent to a new
+    /// destination.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_ms: Option<u64>,
+    /// Number of clients to remember destinations for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_clients: Option<usize>,
+}
+
+/// Part of the client address that is hashed, or that identifies the
+/// client for sticky sessions.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashKey {
+    /// The IP address, so all connections from a host go to the same
+    /// destination.
+    #[default]
+    Ip,
+    /// The IP address and port.
+    Address,
+}
+
+impl HashKey {
+    fn is_default(&self) -> bool {
+        *self == HashKey::default()
+    }
+}
+
+/// Destinations used when the rule destinations are down.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Backups {
+    /// Tiers of destinations in priority order.
+    pub tiers: Vec<Vec<Endpoint>>,
+    /// Time before a failed destination is tried again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_ms: Option<u64>,
+}
+
+/// Settings for picking destinations based on the first bytes sent
+/// by the client.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Sniff {
+    /// Time to wait for the client to send enough data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Routes that are tried in order.
+    pub routes: Vec<SniffRoute>,
+}
+
+/// Destinations for connections where the first bytes match.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SniffRoute {
+    #[serde(rename = "match")]
+    pub matcher: SniffMatch,
+    pub destinations: Vec<Endpoint>,
+}
+
+/// Protocol matched by a sniffing route.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SniffMatch {
+    /// TLS handshake record.
+    Tls,
+    /// HTTP/1.x request line or HTTP/2 connection preface.
+    Http,
+    /// SSH identification string.
+    Ssh,
+    /// Regular expression matched against the first bytes.
+    Regex(String),
+    /// The client did not send anything before the timeout.
+    Timeout,
+}
+
+/// Rate limits for a rule.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct RateLimits {
+    /// Limit for all traffic of the rule.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_rule: Option<RateLimit>,
+    /// Limit for each connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_connection: Option<RateLimit>,
+}
+
+/// Token bucket rate limit.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub bytes_per_second: u64,
+    /// Number of bytes that can be sent at once.
+    pub burst: u64,
+}
+
+/// Settings for terminating TLS from clients.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ListenerTls {
+    /// PEM file with the certificate chain presented to clients.
+    pub cert_file: PathBuf,
+    /// PEM file with the private key for the certificate.
+    pub key_file: PathBuf,
+    /// PEM file with CA certificates used to verify clients. Clients
+    /// have to present a certificate if this is given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ca_file: Option<PathBuf>,
+    /// Patterns that the client identity has to match.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub client_names: Vec<String>,
+    /// Destination pools keyed by client identity pattern.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub client_pools: BTreeMap<String, Vec<Endpoint>>,
+}
+
+/// Settings for TLS connections to the destinations.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct UpstreamTls {
+    /// PEM file with CA certificates used to verify destinations.
+    pub ca_file: PathBuf,
+    /// Name expected in the destination certificate and sent as SNI.
+    pub server_name: String,
+    /// PEM file with the client certificate chain, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert_file: Option<PathBuf>,
+    /// PEM file with the private key for the client certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
 }
 
 pub struct Route {
     pub protocol: Protocol,
-    pub source: SocketAddr,
-    pub destination: SocketAddr,
+    pub source: Endpoint,
+    pub destination: Endpoint,
 }
 
 /// Mode for forwarding rule
@@ -60,6 +722,14 @@ pub struct Route {
 pub enum Mode {
     RoundRobin,
     Broadcast,
+    WeightedRoundRobin,
+    LeastConnections,
+    ConsistentHash,
+    Random,
+    #[serde(rename = "p2c")]
+    PowerOfTwoChoices,
+    PeakEwma,
+    Script,
 }
 
 /// Protocol
@@ -75,31 +745,168 @@ pub enum Error {
     ParseError,
 }
 
+impl Rule {
+    /// All destinations that connections or packets of the rule can
+    /// be forwarded to, not counting the shadow destination.
+    pub fn all_destinations(&self) -> Vec<Endpoint> {
+        let client_pools = self.tls.iter().flat_map(|tls| tls.client_pools.values());
+        let sniff_routes = self.sniff.iter().flat_map(|sniff| &sniff.routes);
+        let backups = self.backups.iter().flat_map(|backups| &backups.tiers);
+        let source_routes = self.source_routes.iter().map(|route| &route.destinations);
+        self.sni
+            .values()
+            .chain(client_pools)
+            .chain(sniff_routes.map(|route| &route.destinations))
+            .chain(backups)
+            .chain(source_routes)
+            .flatten()
+            .chain(&self.destinations)
+            .cloned()
+            .collect()
+    }
+
+    /// Create a new rule without any optional settings.
+    pub fn new(
+        protocol: Protocol,
+        mode: Mode,
+        source: Endpoint,
+        destinations: Vec<Endpoint>,
+    ) -> Rule {
+        Rule {
+            protocol,
+            mode,
+            source,
+            destinations,
+            sni: BTreeMap::new(),
+            upstream_tls: None,
+            tls: None,
+            max_connections: None,
+            max_connections_per_client_ip: None,
+            over_limit: OverLimit::Close,
+            idle_timeout_ms: None,
+            max_lifetime_ms: None,
+            rate_limit: None,
+            zero_copy: false,
+            warm_pool: None,
+            socket_options: SocketOptions::default(),
+            shadow: None,
+            sniff: None,
+            socket_file: None,
+            outbound_bind: None,
+            backups: None,
+            weights: BTreeMap::new(),
+            consistent_hash: None,
+            peak_ewma: None,
+            sticky: None,
+            script: None,
+            source_routes: Vec::new(),
+            strategy: None,
+            seed: None,
+        }
+    }
+}
+
 /// Storage for state information.
 pub struct Database {
     pub rules: Vec<Option<Rule>>,
+    stats: Vec<Arc<RuleStats>>,
+    throttles: Vec<Arc<Throttle>>,
+    registries: Vec<Arc<Registry>>,
+    tables: Vec<Tables>,
 }
 
 impl Database {
     pub fn new() -> Self {
-        Database { rules: Vec::new() }
+        Database {
+            rules: Vec::new(),
+            stats: Vec::new(),
+            throttles: Vec::new(),
+            registries: Vec::new(),
+            tables: Vec::new(),
+        }
     }
 
     /// Create a new rule.
     pub fn create_rule(&mut self, rule: Rule) -> usize {
         let id = self.rules.len();
+        let throttle = Throttle::new(rule.rate_limit.as_ref());
+        let rule_weights = rule.weights.clone();
         self.rules.push(Some(rule));
+        self.stats.push(Arc::new(RuleStats::new()));
+        self.throttles.push(Arc::new(throttle));
+        self.registries.push(Arc::new(Registry::new()));
+        self.tables.push(Tables {
+            weights: Arc::new(Weights::new(&rule_weights)),
+            latencies: Arc::new(Latencies::default()),
+        });
         id
     }
 
+    /// Get the statistics for a rule, if the rule exists.
+    pub fn get_stats(&self, id: usize) -> Option<Arc<RuleStats>> {
+        self.get_rule(id)?;
+        self.stats.get(id).cloned()
+    }
+
+    /// Get the rate limits for a rule, if the rule exists.
+    pub fn get_throttle(&self, id: usize) -> Option<Arc<Throttle>> {
+        self.get_rule(id)?;
+        self.throttles.get(id).cloned()
+    }
+
+    /// Get the active connections for a rule, if the rule exists.
+    pub fn get_registry(&self, id: usize) -> Option<Arc<Registry>> {
+        self.get_rule(id)?;
+        self.registries.get(id).cloned()
+    }
+
+    /// Get the tables shared by the strategies of a rule, if the rule
+    /// exists.
+    pub fn get_tables(&self, id: usize) -> Option<Tables> {
+        self.get_rule(id)?;
+        self.tables.get(id).cloned()
+    }
+
+    /// Get the destination weights for a rule, if the rule exists.
+    pub fn get_weights(&self, id: usize) -> Option<Arc<Weights>> {
+        Some(self.get_tables(id)?.weights)
+    }
+
+    /// Get the smoothed destination latencies for a rule, if the rule
+    /// exists.
+    pub fn get_latencies(&self, id: usize) -> Option<Arc<Latencies>> {
+        Some(self.get_tables(id)?.latencies)
+    }
+
+    /// Change the destination weights of an existing rule, if it
+    /// exists.
+    pub fn set_weights(&mut self, id: usize, weights: BTreeMap<Endpoint, u32>) -> Option<()> {
+        let rule = self.rules.get_mut(id)?.as_mut()?;
+        self.tables[id].weights.set(&weights);
+        rule.weights = weights;
+        Some(())
+    }
+
+    /// Change the rate limits of an existing rule, if it exists.
+    pub fn set_rate_limit(&mut self, id: usize, limits: Option<RateLimits>) -> Option<()> {
+        let rule = self.rules.get_mut(id)?.as_mut()?;
+        rule.rate_limit = limits;
+        self.throttles[id].set_limits(limits.as_ref());
+        Some(())
+    }
+
     /// Remove an existing rule, if it exists.
     pub fn drop_rule(&mut self, id: usize) -> Option<Rule> {
-        mem::replace(&mut self.rules[id], None)
+        self.rules[id].take()
     }
 
     /// Update an existing rule, if it exists.
     pub fn update_rule(&mut self, id: usize, rule: Rule) -> Option<Rule> {
-        mem::replace(&mut self.rules[id], Some(rule))
+        let limits = rule.rate_limit;
+        self.tables[id].weights.set(&rule.weights);
+        let old = self.rules[id].replace(rule);
+        self.throttles[id].set_limits(limits.as_ref());
+        old
     }
 
     /// Get rule from rule identifier.
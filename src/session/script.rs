@@ -0,0 +1,310 @@
+//! Strategy picking destinations by running a script.
+//!
+//! The script is written in [Rhai](https://rhai.rs) and is run for
+//! each connection or packet with the following variables set:
+//!
+//! - `client` is the client address as a string.
+//! - `client_ip` is the IP address of the client as a string, which is
+//!   empty for Unix domain socket clients.
+//! - `protocol` is either `"tcp"` or `"udp"`.
+//! - `time` is the number of seconds since the Unix epoch.
+//! - `destinations` is an array with the destinations as strings.
+//!
+//! The value of the script selects the destinations: a string or an
+//! index into `destinations` selects one destination, an array of
+//! strings selects several, and `()` selects none. If the script fails
+//! or exceeds its limits, the destinations are used in round-robin
+//! fashion instead.
+//!
+//! The script cannot read files or import modules, and the number of
+//! operations it can run is limited. The script file is checked for
+//! changes once per second and is reloaded if it has changed. If the
+//! new version does not compile, the old version is kept.
+
+use crate::session::{
+    rules::Script,
+    strategy::{Context, Pick, RoundRobinStrategy, Selection, Strategy},
+    Endpoint, Protocol,
+};
+use rhai::{module_resolvers::DummyModuleResolver, Array, Dynamic, Engine, Scope, AST};
+use std::{
+    convert::TryFrom,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Number of operations a script can run for each selection if the
+/// rule does not give one.
+pub const DEFAULT_MAX_OPERATIONS: u64 = 100_000;
+
+/// Time between checks for changes to the script file.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct ScriptStrategy {
+    peers: Vec<Endpoint>,
+    /// Destinations as passed to the script.
+    destinations: Array,
+    path: PathBuf,
+    engine: Engine,
+    ast: AST,
+    /// Modification time of the script file when it was last read.
+    modified: Option<SystemTime>,
+    /// When the script file was last checked for changes.
+    checked: Instant,
+    fallback: RoundRobinStrategy,
+}
+
+/// Create a script engine without access to files and with the limits
+/// of the settings.
+fn make_engine(settings: &Script) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .set_max_operations(settings.max_operations.unwrap_or(DEFAULT_MAX_OPERATIONS))
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(4096)
+        .set_max_array_size(1024)
+        .set_max_map_size(1024)
+        .on_print(|text| info!("script: {}", text))
+        .on_debug(|text, _, position| debug!("script at {}: {}", position, text));
+    engine.disable_symbol("eval");
+    engine
+}
+
+/// Read and compile a script file, returning the compiled script and
+/// the modification time of the file.
+fn compile(engine: &Engine, path: &Path) -> Result<(AST, Option<SystemTime>), String> {
+    let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("cannot read script '{}': {}", path.display(), err))?;
+    let ast = engine
+        .compile(text)
+        .map_err(|err| format!("script '{}': {}", path.display(), err))?;
+    Ok((ast, modified))
+}
+
+impl ScriptStrategy {
+    /// Create a strategy running a script, or return an error message
+    /// if the script cannot be read or does not compile.
+    pub fn new(peers: &[Endpoint], settings: &Script) -> Result<ScriptStrategy, String> {
+        debug!("Script strategy with peers {:?}", peers);
+        let engine = make_engine(settings);
+        let (ast, modified) = compile(&engine, &settings.path)?;
+        Ok(ScriptStrategy {
+            peers: peers.to_owned(),
+            destinations: peers.iter().map(|peer| peer.to_string().into()).collect(),
+            path: settings.path.clone(),
+            engine,
+            ast,
+            modified,
+            checked: Instant::now(),
+            fallback: RoundRobinStrategy::new(peers),
+        })
+    }
+
+    /// Compile the script file again if it has changed.
+    fn reload(&mut self) {
+        let modified = fs::metadata(&self.path)
+            .and_then(|meta| meta.modified())
+            .ok();
+        if modified == self.modified {
+            return;
+        }
+        // Remember the time even if the script does not compile, so
+        // that the error is only reported once for each change.
+        self.modified = modified;
+        match compile(&self.engine, &self.path) {
+            Ok((ast, _)) => {
+                info!("reloaded script '{}'", self.path.display());
+                self.ast = ast;
+            }
+            Err(err) => error!("keeping the previous script: {}", err),
+        }
+    }
+
+    /// Run the script for a connection or a packet.
+    fn run(&self, context: &Context) -> Result<Selection, String> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let protocol = match context.protocol {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        };
+        let client_ip = context
+            .client
+            .ip()
+            .map(|ip| ip.to_string())
+            .unwrap_or_default();
+        let mut scope = Scope::new();
+        scope
+            .push("client", context.client.to_string())
+            .push("client_ip", client_ip)
+            .push("protocol", protocol)
+            .push("time", i64::try_from(time).unwrap_or(i64::MAX))
+            .push("destinations", self.destinations.clone());
+        let value = self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast)
+            .map_err(|err| err.to_string())?;
+        self.selection(value)
+    }
+
+    /// Turn the value of the script into a selection.
+    fn selection(&self, value: Dynamic) -> Result<Selection, String> {
+        if value.is_unit() {
+            Ok(Selection::Empty)
+        } else if value.is_int() {
+            let index = value.as_int()?;
+            usize::try_from(index)
+                .ok()
+                .and_then(|index| self.peers.get(index))
+                .map(|peer| Selection::One(peer.clone()))
+                .ok_or_else(|| format!("no destination with index {}", index))
+        } else if value.is_string() {
+            Ok(Selection::One(self.lookup(value)?))
+        } else if value.is_array() {
+            let mut picked = value
+                .into_array()?
+                .into_iter()
+                .map(|value| self.lookup(value))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(match picked.len() {
+                0 => Selection::Empty,
+                1 => Selection::One(picked.remove(0)),
+                _ => Selection::All(picked),
+            })
+        } else {
+            Err(format!("cannot select {}", value.type_name()))
+        }
+    }
+
+    /// Find the destination named by a string value.
+    fn lookup(&self, value: Dynamic) -> Result<Endpoint, String> {
+        let name = value.into_string()?;
+        let endpoint: Endpoint = name
+            .parse()
+            .map_err(|_| format!("'{}' is not an address", name))?;
+        if self.peers.contains(&endpoint) {
+            Ok(endpoint)
+        } else {
+            Err(format!("{} is not a destination", endpoint))
+        }
+    }
+}
+
+impl Strategy for ScriptStrategy {
+    fn select(&mut self, context: &Context, pick: &mut Pick) -> Selection {
+        if self.checked.elapsed() >= RELOAD_INTERVAL {
+            self.checked = Instant::now();
+            self.reload();
+        }
+        match self.run(context) {
+            Ok(selection) => selection,
+            Err(err) => {
+                warn!("script '{}' failed: {}", self.path.display(), err);
+                self.fallback.select(context, pick)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers() -> Vec<Endpoint> {
+        ["127.0.0.1:9001", "127.0.0.1:9002", "127.0.0.1:9003"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect()
+    }
+
+    fn settings(name: &str, text: &str) -> Script {
+        let path =
+            std::env::temp_dir().join(format!("router-{}-{}.rhai", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        Script {
+            path,
+            max_operations: None,
+        }
+    }
+
+    fn select(strategy: &mut ScriptStrategy, client: &str) -> Selection {
+        let client: Endpoint = client.parse().unwrap();
+        let context = Context {
+            rule_id: 0,
+            protocol: Protocol::Udp,
+            client: &client,
+            peeked: None,
+        };
+        strategy.select(&context, &mut Pick::default())
+    }
+
+    #[test]
+    fn test_script() {
+        let peers = peers();
+        let text = r#"
+            let port = parse_int(client.split(":")[1]);
+            if port == 1 { () } else if port == 2 { destinations } else { port % 3 }
+        "#;
+        let settings = settings("script", text);
+        let mut strategy = ScriptStrategy::new(&peers, &settings).unwrap();
+        assert_eq!(select(&mut strategy, "10.0.0.1:1"), Selection::Empty);
+        assert_eq!(
+            select(&mut strategy, "10.0.0.1:2"),
+            Selection::All(peers.clone())
+        );
+        assert_eq!(
+            select(&mut strategy, "10.0.0.1:4"),
+            Selection::One(peers[1].clone())
+        );
+
+        // Changes are picked up, unless they do not compile.
+        fs::write(&settings.path, r#"destinations[2]"#).unwrap();
+        strategy.modified = None;
+        strategy.checked -= RELOAD_INTERVAL;
+        assert_eq!(
+            select(&mut strategy, "10.0.0.1:4"),
+            Selection::One(peers[2].clone())
+        );
+        fs::write(&settings.path, r#"destinations["#).unwrap();
+        strategy.modified = None;
+        strategy.checked -= RELOAD_INTERVAL;
+        assert_eq!(
+            select(&mut strategy, "10.0.0.1:4"),
+            Selection::One(peers[2].clone())
+        );
+        fs::remove_file(&settings.path).unwrap();
+    }
+
+    #[test]
+    fn test_script_errors() {
+        let peers = peers();
+        let settings = settings("script-errors", "let x = ;");
+        let result = ScriptStrategy::new(&peers, &settings);
+        assert!(matches!(result, Err(err) if err.contains("line 1")));
+
+        // Scripts exceeding their limits or returning something else
+        // than destinations use round-robin.
+        for text in &["loop {}", r#""127.0.0.1:80""#, "17"] {
+            fs::write(&settings.path, text).unwrap();
+            let mut strategy = ScriptStrategy::new(&peers, &settings).unwrap();
+            assert_eq!(
+                select(&mut strategy, "10.0.0.1:4"),
+                Selection::One(peers[0].clone())
+            );
+            assert_eq!(
+                select(&mut strategy, "10.0.0.1:4"),
+                Selection::One(peers[1].clone())
+            );
+        }
+
+        // Without destinations, the fallback selects nothing.
+        let mut strategy = ScriptStrategy::new(&[], &settings).unwrap();
+        assert_eq!(select(&mut strategy, "10.0.0.1:4"), Selection::Empty);
+        fs::remove_file(&settings.path).unwrap();
+    }
+}
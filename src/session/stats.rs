@@ -0,0 +1,133 @@
+//! Statistics module.
+//!
+//! Each rule has a set of counters that are updated by the session
+//! running the rule and read by the REST API.
+
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::Mutex,
+};
+use tokio::sync::Notify;
+
+/// Counters for a rule.
+#[derive(Default)]
+pub struct RuleStats {
+    rejected_handshakes: Mutex<BTreeMap<String, u64>>,
+    connections: Mutex<Connections>,
+    rejected_connections: Mutex<BTreeMap<String, u64>>,
+    closed_connections: Mutex<BTreeMap<String, u64>>,
+    closed: Notify,
+}
+
+/// Active connections for a rule.
+#[derive(Default)]
+struct Connections {
+    total: usize,
+    per_client: HashMap<IpAddr, usize>,
+}
+
+/// Snapshot of the counters for a rule.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Report {
+    /// Number of rejected TLS handshakes for each reason.
+    pub rejected_handshakes: BTreeMap<String, u64>,
+    /// Number of active connections.
+    pub active_connections: usize,
+    /// Number of active connections for each client address.
+    pub active_clients: BTreeMap<IpAddr, usize>,
+    /// Number of rejected connections for each reason.
+    pub rejected_connections: BTreeMap<String, u64>,
+    /// Number of closed connections for each reason.
+    pub closed_connections: BTreeMap<String, u64>,
+}
+
+impl RuleStats {
+    pub fn new() -> RuleStats {
+        Default::default()
+    }
+
+    /// Count a rejected TLS handshake.
+    pub fn reject_handshake(&self, reason: &str) {
+        let mut rejected = self.rejected_handshakes.lock().unwrap();
+        *rejected.entry(reason.to_string()).or_insert(0) += 1;
+    }
+
+    /// Register a new connection from a client, unless that would
+    /// exceed one of the limits. If a limit would be exceeded, the
+    /// reason is returned.
+    pub fn try_open(
+        &self,
+        client: Option<IpAddr>,
+        max_total: Option<usize>,
+        max_per_client: Option<usize>,
+    ) -> Result<(), &'static str> {
+        let mut connections = self.connections.lock().unwrap();
+        if max_total.is_some_and(|max| connections.total >= max) {
+            return Err("max-connections");
+        }
+        let count = client
+            .and_then(|client| connections.per_client.get(&client).copied())
+            .unwrap_or(0);
+        if client.is_some() && max_per_client.is_some_and(|max| count >= max) {
+            return Err("max-connections-per-client-ip");
+        }
+        connections.total += 1;
+        if let Some(client) = client {
+            connections.per_client.insert(client, count + 1);
+        }
+        Ok(())
+    }
+
+    /// Remove a connection from a client and wake up any connections
+    /// waiting for a free slot.
+    pub fn close(&self, client: Option<IpAddr>) {
+        {
+            let mut connections = self.connections.lock().unwrap();
+            connections.total -= 1;
+            if let Some(client) = client {
+                if let Some(count) = connections.per_client.get_mut(&client) {
+                    *count -= 1;
+                    if *count == 0 {
+                        connections.per_client.remove(&client);
+                    }
+                }
+            }
+        }
+        self.closed.notify_waiters();
+    }
+
+    /// Notification sent when a connection is closed.
+    pub fn closed(&self) -> &Notify {
+        &self.closed
+    }
+
+    /// Count a rejected connection.
+    pub fn reject_connection(&self, reason: &str) {
+        let mut rejected = self.rejected_connections.lock().unwrap();
+        *rejected.entry(reason.to_string()).or_insert(0) += 1;
+    }
+
+    /// Count a closed connection.
+    pub fn close_connection(&self, reason: &str) {
+        let mut closed = self.closed_connections.lock().unwrap();
+        *closed.entry(reason.to_string()).or_insert(0) += 1;
+    }
+
+    /// Take a snapshot of the counters.
+    pub fn report(&self) -> Report {
+        let connections = self.connections.lock().unwrap();
+        Report {
+            rejected_handshakes: self.rejected_handshakes.lock().unwrap().clone(),
+            active_connections: connections.total,
+            active_clients: connections
+                .per_client
+                .iter()
+                .map(|(k, v)| (*k, *v))
+                .collect(),
+            rejected_connections: self.rejected_connections.lock().unwrap().clone(),
+            closed_connections: self.closed_connections.lock().unwrap().clone(),
+        }
+    }
+}
@@ -1,8 +1,214 @@
-use crate::session::{Mode, Rule};
-use std::{net::SocketAddr, str::FromStr};
+//! Strategies for picking destinations.
+//!
+//! A strategy is asked to select destinations for each connection or
+//! packet, and is given a context with what is known about the client
+//! at that point. After the selection, the session reports the
+//! outcome for the selected destinations, which allows strategies to
+//! track the health and load of destinations.
+//!
+//! Besides the strategies for the modes of a rule, library users can
+//! add their own strategies to the [`StrategyRegistry`] under a name.
+//! Rules refer to them in the `strategy` field, together with
+//! parameters that are passed to the constructor as JSON.
+
+use crate::session::{
+    rules::{
+        Backups, ConsistentHash, HashKey, NamedStrategy, PeakEwma, PortRange, SourceRoute, Sticky,
+    },
+    script::ScriptStrategy,
+    Endpoint, Mode, Protocol, Rule,
+};
+use ipnet::IpNet;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, OnceLock, RwLock},
+    time::{Duration, Instant},
+};
+
+/// Time before a failed destination is tried again if the rule does
+/// not give one.
+pub const DEFAULT_RETRY: Duration = Duration::from_secs(10);
+
+/// Number of points on the hash ring for each destination if the rule
+/// does not give one.
+pub const DEFAULT_VIRTUAL_NODES: usize = 160;
+
+/// Time over which the smoothed latency of a destination decays if
+/// the rule does not give one.
+pub const DEFAULT_DECAY: Duration = Duration::from_secs(10);
+
+/// Latency assumed for destinations without a measured latency if the
+/// rule does not give one.
+pub const DEFAULT_PENALTY: Duration = Duration::from_millis(100);
+
+/// Time a client stays with its destination without traffic if the
+/// rule does not give one.
+pub const DEFAULT_STICKY_TTL: Duration = Duration::from_secs(300);
+
+/// Number of clients remembered by a sticky strategy if the rule does
+/// not give one.
+pub const DEFAULT_STICKY_CLIENTS: usize = 10_000;
+
+/// Weight of the latest connect time in the recent latency of a
+/// destination.
+const LATENCY_DECAY: f64 = 0.3;
+
+/// Index in the pick of a sticky strategy for destinations selected by
+/// the base strategy.
+const BASE: usize = 0;
+
+/// Index in the pick of a sticky strategy for destinations remembered
+/// for the client.
+const REMEMBERED: usize = 1;
 
 pub trait Strategy {
-    fn destinations(&mut self) -> Vec<SocketAddr>;
+    /// Select the destinations for a connection or a packet.
+    ///
+    /// The pick is empty when this is called. Strategies that pass the
+    /// selection on to other strategies record in it which one made
+    /// the selection, and the outcomes for the selected destinations
+    /// are reported with the same pick.
+    fn select(&mut self, context: &Context, pick: &mut Pick) -> Selection;
+
+    /// Select the destinations for a connection or a packet among the
+    /// available destinations.
+    ///
+    /// Strategies that count the connections they select should pick
+    /// only available destinations, so that they do not count picks
+    /// that are thrown away. Other strategies can select as usual, and
+    /// the caller throws away the destinations that are not available.
+    fn select_available(
+        &mut self,
+        context: &Context,
+        pick: &mut Pick,
+        _available: &dyn Fn(&Endpoint) -> bool,
+    ) -> Selection {
+        self.select(context, pick)
+    }
+
+    /// Tell the strategy about the outcome for a selected destination,
+    /// together with the pick of the selection.
+    ///
+    /// For TCP, a selected destination gets either `Connected` or
+    /// `Failed`, except when a pooled connection is used, followed by
+    /// exactly one `Closed`. For UDP, each packet gets either `Sent`
+    /// or `Failed`. If the strategy measures replies, a reply from a
+    /// destination gets `Replied`, and a destination that does not
+    /// reply in time gets `Failed`.
+    fn outcome(&mut self, _pick: &Pick, _destination: &Endpoint, _outcome: Outcome) {}
+
+    /// Check if the strategy measures the response time of UDP
+    /// destinations. Packets from destinations are then taken as
+    /// replies instead of being forwarded like packets from clients.
+    fn measures_replies(&self) -> bool {
+        false
+    }
+}
+
+/// Which strategies made a selection, for strategies that pass the
+/// selection on to other strategies.
+///
+/// Each of these pushes the index of the strategy it passed the
+/// selection to after that strategy has selected, so the outermost
+/// strategy finds its index last, and passes the rest on with the
+/// outcome.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Pick {
+    path: Vec<usize>,
+}
+
+impl Pick {
+    /// Record the index of the strategy that made the selection.
+    pub fn push(&mut self, index: usize) {
+        self.path.push(index);
+    }
+
+    /// Get the index pushed last together with the pick of the inner
+    /// strategy, if any index was pushed.
+    pub fn split(&self) -> Option<(usize, Pick)> {
+        let (&index, inner) = self.path.split_last()?;
+        Some((
+            index,
+            Pick {
+                path: inner.to_vec(),
+            },
+        ))
+    }
+}
+
+/// What is known about a connection or packet when destinations are
+/// selected for it.
+#[derive(Debug, Clone, Copy)]
+pub struct Context<'a> {
+    pub rule_id: usize,
+    pub protocol: Protocol,
+    pub client: &'a Endpoint,
+    /// Bytes received from the client before selecting, which is the
+    /// packet for UDP and the bytes read for protocol sniffing or
+    /// server name routing for TCP.
+    pub peeked: Option<&'a [u8]>,
+}
+
+/// Destinations selected by a strategy.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Selection {
+    /// Forward to a single destination.
+    One(Endpoint),
+    /// Send a copy to each destination, which only makes sense for
+    /// UDP.
+    All(Vec<Endpoint>),
+    /// No destination is available.
+    Empty,
+}
+
+/// Outcome of forwarding to a selected destination.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Outcome {
+    /// A connection was established in the given time.
+    Connected(Duration),
+    /// A packet was sent.
+    Sent,
+    /// Connecting or sending failed.
+    Failed,
+    /// A reply to packets sent to the destination arrived in the
+    /// given time, counted from the first packet sent since the
+    /// previous reply.
+    Replied(Duration),
+    /// A connection has closed.
+    Closed,
+}
+
+impl Selection {
+    /// Get the selected destinations.
+    pub fn destinations(&self) -> &[Endpoint] {
+        match self {
+            Selection::One(destination) => std::slice::from_ref(destination),
+            Selection::All(destinations) => destinations,
+            Selection::Empty => &[],
+        }
+    }
+
+    /// Keep only the destinations matching a predicate.
+    fn filter<F>(self, mut keep: F) -> Selection
+    where
+        F: FnMut(&Endpoint) -> bool,
+    {
+        match self {
+            Selection::One(destination) if keep(&destination) => Selection::One(destination),
+            Selection::All(mut destinations) => {
+                destinations.retain(keep);
+                if destinations.is_empty() {
+                    Selection::Empty
+                } else {
+                    Selection::All(destinations)
+                }
+            }
+            _ => Selection::Empty,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -15,19 +221,201 @@ pub struct StrategyFactory;
 impl StrategyFactory {
     /// Create a boxed strategy based on a mode and a vector of
     /// destinations.
-    pub fn make(rule: &Rule) -> Box<dyn Strategy + Send> {
-        match rule.mode {
-            Mode::Broadcast => Box::new(BroadcastStrategy::new(&rule.destinations)),
-            Mode::RoundRobin => Box::new(RoundRobinStrategy::new(&rule.destinations)),
+    pub fn make(rule: &Rule, tables: &Tables) -> Box<dyn Strategy + Send> {
+        let mut strategy = match &rule.backups {
+            Some(backups) => Box::new(TieredStrategy::new(rule, backups, tables)),
+            None => Self::build(rule, &rule.destinations, tables),
+        };
+        if !rule.source_routes.is_empty() {
+            let routes = &rule.source_routes;
+            strategy = Box::new(SourceRoutingStrategy::new(rule, routes, strategy, tables));
+        }
+        match &rule.sticky {
+            Some(settings) => Box::new(StickyStrategy::new(strategy, settings)),
+            None => strategy,
+        }
+    }
+
+    /// Create a boxed strategy for a pool of destinations using the
+    /// mode and settings of the rule, or the named strategy of the
+    /// rule if it has one. The weights are only used in weighted
+    /// round-robin mode and the latencies in peak-EWMA mode.
+    pub fn build(
+        rule: &Rule,
+        destinations: &[Endpoint],
+        tables: &Tables,
+    ) -> Box<dyn Strategy + Send> {
+        if let Some(named) = &rule.strategy {
+            match StrategyRegistry::build(named, destinations) {
+                Ok(strategy) => return strategy,
+                Err(err) => error!("using mode {} instead: {}", rule.mode, err),
+            }
+        }
+        Self::build_mode(rule, rule.mode, destinations, tables)
+    }
+
+    /// Create a boxed strategy for a pool of destinations using a
+    /// mode and the settings of the rule.
+    fn build_mode(
+        rule: &Rule,
+        mode: Mode,
+        destinations: &[Endpoint],
+        tables: &Tables,
+    ) -> Box<dyn Strategy + Send> {
+        match mode {
+            Mode::Broadcast => Box::new(BroadcastStrategy::new(destinations)),
+            Mode::RoundRobin => Box::new(RoundRobinStrategy::new(destinations)),
+            Mode::WeightedRoundRobin => Box::new(WeightedRoundRobinStrategy::new(
+                destinations,
+                tables.weights.clone(),
+            )),
+            Mode::LeastConnections => Box::new(LeastConnectionsStrategy::new(destinations)),
+            Mode::ConsistentHash => Box::new(ConsistentHashStrategy::new(
+                destinations,
+                &rule.consistent_hash.unwrap_or_default(),
+            )),
+            Mode::Random => Box::new(RandomStrategy::new(destinations, rule.seed)),
+            Mode::PowerOfTwoChoices => {
+                Box::new(PowerOfTwoChoicesStrategy::new(destinations, rule.seed))
+            }
+            Mode::PeakEwma => Box::new(PeakEwmaStrategy::new(
+                destinations,
+                &rule.peak_ewma.unwrap_or_default(),
+                tables.latencies.clone(),
+            )),
+            Mode::Script => {
+                let script = rule
+                    .script
+                    .as_ref()
+                    .ok_or_else(|| "no script given".to_string())
+                    .and_then(|settings| ScriptStrategy::new(destinations, settings));
+                match script {
+                    Ok(strategy) => Box::new(strategy),
+                    Err(err) => {
+                        error!("using round-robin instead: {}", err);
+                        Box::new(RoundRobinStrategy::new(destinations))
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Constructor of a named strategy. It is given the destinations of
+/// a pool and the parameters from the rule, and returns an error
+/// message if the parameters are not valid.
+pub type Constructor = dyn Fn(&[Endpoint], &serde_json::Value) -> Result<Box<dyn Strategy + Send>, String>
+    + Send
+    + Sync;
+
+/// Registry of named strategies, shared by all rules of the process.
+pub struct StrategyRegistry;
+
+fn constructors() -> &'static RwLock<BTreeMap<String, Arc<Constructor>>> {
+    static CONSTRUCTORS: OnceLock<RwLock<BTreeMap<String, Arc<Constructor>>>> = OnceLock::new();
+    CONSTRUCTORS.get_or_init(Default::default)
+}
+
+impl StrategyRegistry {
+    /// Register a strategy constructor under a name, replacing any
+    /// constructor already registered under the name. Strategies have
+    /// to be registered before rules using them are read.
+    pub fn register<F>(name: &str, constructor: F)
+    where
+        F: Fn(&[Endpoint], &serde_json::Value) -> Result<Box<dyn Strategy + Send>, String>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let mut constructors = constructors().write().unwrap();
+        constructors.insert(name.to_string(), Arc::new(constructor));
+    }
+
+    /// Get the names of all registered strategies.
+    pub fn names() -> Vec<String> {
+        constructors().read().unwrap().keys().cloned().collect()
+    }
+
+    /// Create a named strategy for a pool of destinations. Rules are
+    /// validated by creating their strategy, so constructors should
+    /// not have side effects.
+    pub fn build(
+        named: &NamedStrategy,
+        destinations: &[Endpoint],
+    ) -> Result<Box<dyn Strategy + Send>, String> {
+        let constructor = constructors().read().unwrap().get(&named.name).cloned();
+        match constructor {
+            Some(constructor) => constructor(destinations, &named.params)
+                .map_err(|err| format!("strategy '{}': {}", named.name, err)),
+            None => Err(format!("no strategy named '{}'", named.name)),
         }
     }
 }
 
+/// Weights of the destinations of a rule. The weights are shared by
+/// all strategies of the rule so that they can be changed while the
+/// rule is running. Destinations without a weight have weight 1.
+#[derive(Default)]
+pub struct Weights {
+    table: RwLock<BTreeMap<Endpoint, u32>>,
+}
+
+impl Weights {
+    pub fn new(weights: &BTreeMap<Endpoint, u32>) -> Weights {
+        Weights {
+            table: RwLock::new(weights.clone()),
+        }
+    }
+
+    /// Replace all weights.
+    pub fn set(&self, weights: &BTreeMap<Endpoint, u32>) {
+        *self.table.write().unwrap() = weights.clone();
+    }
+
+    /// Get the weight of each of the destinations.
+    pub fn get(&self, peers: &[Endpoint]) -> Vec<u32> {
+        let table = self.table.read().unwrap();
+        peers
+            .iter()
+            .map(|peer| table.get(peer).copied().unwrap_or(1))
+            .collect()
+    }
+}
+
+/// Smoothed latencies of the destinations of a rule, as last measured
+/// by its strategies, so that they can be inspected while the rule is
+/// running.
+#[derive(Default)]
+pub struct Latencies {
+    table: RwLock<BTreeMap<Endpoint, Duration>>,
+}
+
+impl Latencies {
+    /// Record the latest smoothed latency of a destination.
+    pub fn set(&self, destination: &Endpoint, latency: Duration) {
+        let mut table = self.table.write().unwrap();
+        table.insert(destination.clone(), latency);
+    }
+
+    /// Get the latest smoothed latency of each destination with one.
+    pub fn get(&self) -> BTreeMap<Endpoint, Duration> {
+        self.table.read().unwrap().clone()
+    }
+}
+
+/// Tables of a rule that are shared by its strategies and the REST
+/// API.
+#[derive(Clone, Default)]
+pub struct Tables {
+    pub weights: Arc<Weights>,
+    pub latencies: Arc<Latencies>,
+}
+
 /// Strategy for broadcasting packets to all destinations. Only makes
 /// sense for UDP.
 #[derive(Clone)]
 pub struct BroadcastStrategy {
-    peers: Vec<SocketAddr>,
+    peers: Vec<Endpoint>,
 }
 
 /// Strategy for sending packets or connections to destinations
@@ -35,11 +423,151 @@ pub struct BroadcastStrategy {
 #[derive(Clone)]
 pub struct RoundRobinStrategy {
     next: usize,
-    peers: Vec<SocketAddr>,
+    peers: Vec<Endpoint>,
+}
+
+/// Strategy for sending packets or connections to destinations in
+/// proportion to their weights. Picks are spread out using the smooth
+/// weighted round-robin algorithm of nginx, so a destination with a
+/// high weight does not get all its picks in a row.
+pub struct WeightedRoundRobinStrategy {
+    peers: Vec<Endpoint>,
+    /// Current weight of each destination, which grows by the weight
+    /// of the destination on each pick and shrinks by the total weight
+    /// when the destination is picked.
+    current: Vec<i64>,
+    weights: Arc<Weights>,
+}
+
+/// Strategy for sending connections to the destination with the
+/// fewest active connections. Ties are broken in a round-robin
+/// fashion, so destinations that are equally loaded take turns. Only
+/// makes sense for TCP.
+pub struct LeastConnectionsStrategy {
+    next: usize,
+    peers: Vec<Endpoint>,
+    /// Number of active connections to each destination.
+    active: Vec<usize>,
+}
+
+/// Strategy mapping each client to a destination using consistent
+/// hashing. Each destination has a number of points on a hash ring
+/// and a client is sent to the destination owning the first point at
+/// or after the hash of the client, so adding or removing a
+/// destination only moves the clients between it and its neighbors.
+pub struct ConsistentHashStrategy {
+    peers: Vec<Endpoint>,
+    /// Points on the ring with the index of the destination owning
+    /// them, sorted by hash.
+    ring: Vec<(u64, usize)>,
+    key: HashKey,
+}
+
+/// Strategy for sending packets or connections to a destination
+/// picked at random.
+pub struct RandomStrategy {
+    peers: Vec<Endpoint>,
+    rng: StdRng,
+}
+
+/// Strategy picking two destinations at random and using the one with
+/// the fewest connections in flight, or the lowest recent connect time
+/// if they have equally many. Unlike always picking the least loaded
+/// destination, this keeps many routers sharing the destinations from
+/// all sending their next connections to the same destination.
+pub struct PowerOfTwoChoicesStrategy {
+    peers: Vec<Endpoint>,
+    /// Number of TCP connections in flight to each destination.
+    in_flight: Vec<usize>,
+    /// Moving average of the connect time to each destination in
+    /// seconds, if any connection has been made.
+    latency: Vec<Option<f64>>,
+    rng: StdRng,
+}
+
+/// Strategy picking the destination with the lowest connect time,
+/// multiplied by the number of connections in flight to it plus one.
+///
+/// The connect time of each destination is a moving average that
+/// jumps to the latest connect time when it is higher, so that a
+/// destination that slows down is avoided at once, and otherwise
+/// decays towards the connect times measured. Without new connections,
+/// the average decays towards zero, so that a destination that was
+/// slow is tried again after a while. Destinations without a measured
+/// connect time are assumed to have the penalty latency.
+pub struct PeakEwmaStrategy {
+    peers: Vec<Endpoint>,
+    /// Number of TCP connections in flight to each destination.
+    in_flight: Vec<usize>,
+    /// Smoothed connect or response time of each destination in
+    /// seconds and the time it was measured, if any has been measured.
+    latency: Vec<Option<(f64, Instant)>>,
+    /// When the first UDP packet without a reply was sent to each
+    /// destination.
+    waiting: Vec<Option<Instant>>,
+    /// Time constant of the decay in seconds.
+    decay: f64,
+    /// Latency in seconds of destinations without a connect time.
+    penalty: f64,
+    /// Index to start looking for the cheapest destination from, so
+    /// that destinations with the same cost take turns.
+    next: usize,
+    latencies: Arc<Latencies>,
+}
+
+/// Strategy using tiers of destinations in priority order, where a
+/// tier is only used if all destinations of the tiers before it have
+/// failed recently.
+pub struct TieredStrategy {
+    tiers: Vec<Tier>,
+    /// When each failed destination last failed.
+    failed: HashMap<Endpoint, Instant>,
+    retry: Duration,
+}
+
+/// Strategy sending each client to the destination picked for it by
+/// another strategy, until the client has been idle for a while or
+/// the destination has failed.
+pub struct StickyStrategy {
+    base: Box<dyn Strategy + Send>,
+    key: HashKey,
+    ttl: Duration,
+    max_clients: usize,
+    clients: HashMap<Endpoint, Affinity>,
+}
+
+/// Destination of a client, the pick of the base strategy that
+/// selected it and when the client was last seen.
+struct Affinity {
+    destination: Endpoint,
+    pick: Pick,
+    used: Instant,
+}
+
+/// Strategy using the destinations of the first source route matching
+/// the client, or a default strategy if no route matches.
+pub struct SourceRoutingStrategy {
+    routes: Vec<Source>,
+    /// Strategies of the routes, in the same order, followed by the
+    /// default strategy.
+    strategies: Vec<Box<dyn Strategy + Send>>,
+}
+
+/// Clients matched by a source route.
+struct Source {
+    network: IpNet,
+    port_range: Option<PortRange>,
+}
+
+/// Destinations of a tier together with the strategy picking among
+/// them.
+struct Tier {
+    peers: Vec<Endpoint>,
+    strategy: Box<dyn Strategy + Send>,
 }
 
 impl BroadcastStrategy {
-    pub fn new(peers: &[SocketAddr]) -> BroadcastStrategy {
+    pub fn new(peers: &[Endpoint]) -> BroadcastStrategy {
         debug!("Broadcast strategy with peers {:?}", peers);
         BroadcastStrategy {
             peers: peers.to_owned(),
@@ -48,7 +576,7 @@ impl BroadcastStrategy {
 }
 
 impl RoundRobinStrategy {
-    pub fn new(peers: &[SocketAddr]) -> RoundRobinStrategy {
+    pub fn new(peers: &[Endpoint]) -> RoundRobinStrategy {
         debug!("RoundRobin strategy with peers {:?}", peers);
         RoundRobinStrategy {
             next: 0,
@@ -57,15 +585,304 @@ impl RoundRobinStrategy {
     }
 }
 
+impl WeightedRoundRobinStrategy {
+    pub fn new(peers: &[Endpoint], weights: Arc<Weights>) -> WeightedRoundRobinStrategy {
+        debug!("WeightedRoundRobin strategy with peers {:?}", peers);
+        WeightedRoundRobinStrategy {
+            peers: peers.to_owned(),
+            current: vec![0; peers.len()],
+            weights,
+        }
+    }
+}
+
+impl LeastConnectionsStrategy {
+    pub fn new(peers: &[Endpoint]) -> LeastConnectionsStrategy {
+        debug!("LeastConnections strategy with peers {:?}", peers);
+        LeastConnectionsStrategy {
+            next: 0,
+            peers: peers.to_owned(),
+            active: vec![0; peers.len()],
+        }
+    }
+}
+
+impl ConsistentHashStrategy {
+    pub fn new(peers: &[Endpoint], settings: &ConsistentHash) -> ConsistentHashStrategy {
+        debug!("ConsistentHash strategy with peers {:?}", peers);
+        let virtual_nodes = settings.virtual_nodes.unwrap_or(DEFAULT_VIRTUAL_NODES);
+        let mut ring: Vec<(u64, usize)> = peers
+            .iter()
+            .enumerate()
+            .flat_map(|(index, peer)| {
+                (0..virtual_nodes).map(move |node| {
+                    let point = format!("{}#{}", peer, node);
+                    (hash(point.as_bytes()), index)
+                })
+            })
+            .collect();
+        ring.sort_unstable();
+        ConsistentHashStrategy {
+            peers: peers.to_owned(),
+            ring,
+            key: settings.key,
+        }
+    }
+
+    /// Get the index of the destination for a client.
+    fn lookup(&self, client: &Endpoint) -> usize {
+        let key = match (self.key, client.ip()) {
+            (HashKey::Ip, Some(ip)) => hash(ip.to_string().as_bytes()),
+            _ => hash(client.to_string().as_bytes()),
+        };
+        let position = self.ring.partition_point(|&(point, _)| point < key);
+        self.ring[position % self.ring.len()].1
+    }
+}
+
+impl StickyStrategy {
+    pub fn new(base: Box<dyn Strategy + Send>, settings: &Sticky) -> StickyStrategy {
+        StickyStrategy {
+            base,
+            key: settings.key,
+            ttl: settings
+                .ttl_ms
+                .map_or(DEFAULT_STICKY_TTL, Duration::from_millis),
+            max_clients: settings.max_clients.unwrap_or(DEFAULT_STICKY_CLIENTS),
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Get the part of the client address that identifies the client.
+    fn client_key(&self, client: &Endpoint) -> Endpoint {
+        match (self.key, client.ip()) {
+            (HashKey::Ip, Some(ip)) => Endpoint::Inet(SocketAddr::new(ip, 0)),
+            _ => client.clone(),
+        }
+    }
+
+    /// Remember the destination of a client. If the table is full,
+    /// idle clients are forgotten, or the least recently seen client
+    /// if none is idle.
+    fn remember(&mut self, client: Endpoint, destination: Endpoint, pick: Pick, now: Instant) {
+        if self.clients.len() >= self.max_clients && !self.clients.contains_key(&client) {
+            let ttl = self.ttl;
+            self.clients
+                .retain(|_, affinity| now.saturating_duration_since(affinity.used) < ttl);
+            if self.clients.len() >= self.max_clients {
+                let oldest = self
+                    .clients
+                    .iter()
+                    .min_by_key(|(_, affinity)| affinity.used)
+                    .map(|(client, _)| client.clone());
+                if let Some(oldest) = oldest {
+                    self.clients.remove(&oldest);
+                }
+            }
+        }
+        let affinity = Affinity {
+            destination,
+            pick,
+            used: now,
+        };
+        self.clients.insert(client, affinity);
+    }
+}
+
+impl SourceRoutingStrategy {
+    pub fn new(
+        rule: &Rule,
+        routes: &[SourceRoute],
+        default: Box<dyn Strategy + Send>,
+        tables: &Tables,
+    ) -> SourceRoutingStrategy {
+        let strategies = routes
+            .iter()
+            .map(|route| match route.mode {
+                Some(mode) => StrategyFactory::build_mode(rule, mode, &route.destinations, tables),
+                None => StrategyFactory::build(rule, &route.destinations, tables),
+            })
+            .chain(std::iter::once(default))
+            .collect();
+        SourceRoutingStrategy {
+            routes: routes
+                .iter()
+                .map(|route| Source {
+                    network: route.network,
+                    port_range: route.port_range,
+                })
+                .collect(),
+            strategies,
+        }
+    }
+}
+
+impl Source {
+    fn matches(&self, client: &Endpoint) -> bool {
+        match client.inet() {
+            Some(addr) => {
+                self.network.contains(&addr.ip())
+                    && self
+                        .port_range
+                        .is_none_or(|range| range.contains(addr.port()))
+            }
+            None => false,
+        }
+    }
+}
+
+/// Create a random number generator from a seed, or from the
+/// operating system if there is no seed.
+fn make_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+impl RandomStrategy {
+    pub fn new(peers: &[Endpoint], seed: Option<u64>) -> RandomStrategy {
+        debug!("Random strategy with peers {:?}", peers);
+        RandomStrategy {
+            peers: peers.to_owned(),
+            rng: make_rng(seed),
+        }
+    }
+}
+
+impl PowerOfTwoChoicesStrategy {
+    pub fn new(peers: &[Endpoint], seed: Option<u64>) -> PowerOfTwoChoicesStrategy {
+        debug!("PowerOfTwoChoices strategy with peers {:?}", peers);
+        PowerOfTwoChoicesStrategy {
+            peers: peers.to_owned(),
+            in_flight: vec![0; peers.len()],
+            latency: vec![None; peers.len()],
+            rng: make_rng(seed),
+        }
+    }
+
+    /// Check if the first destination is a better choice than the
+    /// second. Destinations without a connect time yet are preferred,
+    /// so that they get one.
+    fn is_better(&self, first: usize, second: usize) -> bool {
+        let load = |index: usize| (self.in_flight[index], self.latency[index].unwrap_or(0.0));
+        let (first, second) = (load(first), load(second));
+        first.0 < second.0 || (first.0 == second.0 && first.1 < second.1)
+    }
+}
+
+impl PeakEwmaStrategy {
+    pub fn new(
+        peers: &[Endpoint],
+        settings: &PeakEwma,
+        latencies: Arc<Latencies>,
+    ) -> PeakEwmaStrategy {
+        debug!("PeakEwma strategy with peers {:?}", peers);
+        let decay = settings
+            .decay_ms
+            .map_or(DEFAULT_DECAY, Duration::from_millis);
+        let penalty = settings
+            .penalty_ms
+            .map_or(DEFAULT_PENALTY, Duration::from_millis);
+        PeakEwmaStrategy {
+            peers: peers.to_owned(),
+            in_flight: vec![0; peers.len()],
+            latency: vec![None; peers.len()],
+            waiting: vec![None; peers.len()],
+            decay: decay.as_secs_f64(),
+            penalty: penalty.as_secs_f64(),
+            next: 0,
+            latencies,
+        }
+    }
+
+    /// Get the smoothed latency of a destination at a point in time.
+    /// A destination that has not replied to a packet yet has at least
+    /// the latency of the time spent waiting for the reply.
+    fn estimate(&self, index: usize, now: Instant) -> f64 {
+        let estimate = match self.latency[index] {
+            Some((latency, when)) => {
+                let elapsed = now.saturating_duration_since(when).as_secs_f64();
+                latency * (-elapsed / self.decay).exp()
+            }
+            None => self.penalty,
+        };
+        match self.waiting[index] {
+            Some(sent) => estimate.max(now.saturating_duration_since(sent).as_secs_f64()),
+            None => estimate,
+        }
+    }
+
+    /// Add a measured latency, in seconds, to the smoothed latency of
+    /// a destination.
+    fn observe(&mut self, index: usize, measured: f64) {
+        let now = Instant::now();
+        let latency = match self.latency[index] {
+            Some((latency, when)) if measured < latency => {
+                let elapsed = now.saturating_duration_since(when).as_secs_f64();
+                let weight = (-elapsed / self.decay).exp();
+                latency * weight + measured * (1.0 - weight)
+            }
+            _ => measured,
+        };
+        self.latency[index] = Some((latency, now));
+        self.latencies
+            .set(&self.peers[index], Duration::from_secs_f64(latency));
+    }
+}
+
+/// Hash bytes using 64-bit FNV-1a followed by the finalizer of
+/// MurmurHash3, which spreads similar inputs such as addresses that
+/// only differ in the last digit over the whole range. Unlike the
+/// hasher of the standard library, the result is the same across
+/// builds, so clients keep their destination when the router is
+/// upgraded.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+impl TieredStrategy {
+    /// Create a strategy with the rule destinations as the first tier,
+    /// followed by the backup tiers.
+    pub fn new(rule: &Rule, backups: &Backups, tables: &Tables) -> TieredStrategy {
+        let tiers = std::iter::once(&rule.destinations)
+            .chain(&backups.tiers)
+            .map(|peers| Tier {
+                peers: peers.clone(),
+                strategy: StrategyFactory::build(rule, peers, tables),
+            })
+            .collect();
+        TieredStrategy {
+            tiers,
+            failed: HashMap::new(),
+            retry: backups
+                .retry_ms
+                .map_or(DEFAULT_RETRY, Duration::from_millis),
+        }
+    }
+}
+
 impl Strategy for BroadcastStrategy {
-    fn destinations(&mut self) -> Vec<SocketAddr> {
-        self.peers.clone()
+    fn select(&mut self, _context: &Context, _pick: &mut Pick) -> Selection {
+        Selection::All(self.peers.clone())
     }
 }
 
 impl Strategy for RoundRobinStrategy {
-    fn destinations(&mut self) -> Vec<SocketAddr> {
-        let result = vec![self.peers[self.next]];
+    fn select(&mut self, _context: &Context, _pick: &mut Pick) -> Selection {
+        if self.peers.is_empty() {
+            return Selection::Empty;
+        }
+        let result = Selection::One(self.peers[self.next].clone());
         self.next += 1;
         if self.next >= self.peers.len() {
             self.next = 0;
@@ -74,6 +891,349 @@ impl Strategy for RoundRobinStrategy {
     }
 }
 
+impl Strategy for WeightedRoundRobinStrategy {
+    fn select(&mut self, _context: &Context, _pick: &mut Pick) -> Selection {
+        let mut weights = self.weights.get(&self.peers);
+        // Destinations with weight zero are not used, unless all of
+        // them have weight zero.
+        if weights.iter().all(|&weight| weight == 0) {
+            weights.iter_mut().for_each(|weight| *weight = 1);
+        }
+        let total: i64 = weights.iter().map(|&weight| i64::from(weight)).sum();
+        let mut best: Option<usize> = None;
+        for (index, &weight) in weights.iter().enumerate() {
+            if weight == 0 {
+                continue;
+            }
+            self.current[index] += i64::from(weight);
+            if best.is_none_or(|best| self.current[index] > self.current[best]) {
+                best = Some(index);
+            }
+        }
+        let best = match best {
+            Some(best) => best,
+            None => return Selection::Empty,
+        };
+        self.current[best] -= total;
+        Selection::One(self.peers[best].clone())
+    }
+}
+
+impl Strategy for LeastConnectionsStrategy {
+    fn select(&mut self, context: &Context, pick: &mut Pick) -> Selection {
+        self.select_available(context, pick, &|_| true)
+    }
+
+    fn select_available(
+        &mut self,
+        _context: &Context,
+        _pick: &mut Pick,
+        available: &dyn Fn(&Endpoint) -> bool,
+    ) -> Selection {
+        let count = self.peers.len();
+        let best = (0..count)
+            .map(|offset| (self.next + offset) % count)
+            .filter(|&index| available(&self.peers[index]))
+            .min_by_key(|&index| self.active[index]);
+        let best = match best {
+            Some(best) => best,
+            None => return Selection::Empty,
+        };
+        self.next = (best + 1) % count;
+        self.active[best] += 1;
+        Selection::One(self.peers[best].clone())
+    }
+
+    fn outcome(&mut self, _pick: &Pick, destination: &Endpoint, outcome: Outcome) {
+        if outcome != Outcome::Closed {
+            return;
+        }
+        if let Some(index) = self.peers.iter().position(|peer| peer == destination) {
+            self.active[index] = self.active[index].saturating_sub(1);
+        }
+    }
+}
+
+impl Strategy for ConsistentHashStrategy {
+    fn select(&mut self, context: &Context, _pick: &mut Pick) -> Selection {
+        if self.ring.is_empty() {
+            return Selection::Empty;
+        }
+        Selection::One(self.peers[self.lookup(context.client)].clone())
+    }
+}
+
+impl Strategy for RandomStrategy {
+    fn select(&mut self, context: &Context, pick: &mut Pick) -> Selection {
+        self.select_available(context, pick, &|_| true)
+    }
+
+    fn select_available(
+        &mut self,
+        _context: &Context,
+        _pick: &mut Pick,
+        available: &dyn Fn(&Endpoint) -> bool,
+    ) -> Selection {
+        let candidates: Vec<usize> = (0..self.peers.len())
+            .filter(|&index| available(&self.peers[index]))
+            .collect();
+        if candidates.is_empty() {
+            return Selection::Empty;
+        }
+        let index = candidates[self.rng.gen_range(0..candidates.len())];
+        Selection::One(self.peers[index].clone())
+    }
+}
+
+impl Strategy for PowerOfTwoChoicesStrategy {
+    fn select(&mut self, context: &Context, pick: &mut Pick) -> Selection {
+        self.select_available(context, pick, &|_| true)
+    }
+
+    fn select_available(
+        &mut self,
+        context: &Context,
+        _pick: &mut Pick,
+        available: &dyn Fn(&Endpoint) -> bool,
+    ) -> Selection {
+        let candidates: Vec<usize> = (0..self.peers.len())
+            .filter(|&index| available(&self.peers[index]))
+            .collect();
+        let count = candidates.len();
+        if count == 0 {
+            return Selection::Empty;
+        }
+        let first = self.rng.gen_range(0..count);
+        let mut best = candidates[first];
+        if count > 1 {
+            // Pick a second destination different from the first.
+            let mut second = self.rng.gen_range(0..count - 1);
+            if second >= first {
+                second += 1;
+            }
+            if self.is_better(candidates[second], best) {
+                best = candidates[second];
+            }
+        }
+        // Packets are never closed, so only connections are counted.
+        if context.protocol == Protocol::Tcp {
+            self.in_flight[best] += 1;
+        }
+        Selection::One(self.peers[best].clone())
+    }
+
+    fn outcome(&mut self, _pick: &Pick, destination: &Endpoint, outcome: Outcome) {
+        let index = match self.peers.iter().position(|peer| peer == destination) {
+            Some(index) => index,
+            None => return,
+        };
+        match outcome {
+            Outcome::Connected(latency) | Outcome::Replied(latency) => {
+                let latest = latency.as_secs_f64();
+                self.latency[index] = Some(match self.latency[index] {
+                    Some(average) => average + LATENCY_DECAY * (latest - average),
+                    None => latest,
+                });
+            }
+            Outcome::Closed => {
+                self.in_flight[index] = self.in_flight[index].saturating_sub(1);
+            }
+            Outcome::Sent | Outcome::Failed => {}
+        }
+    }
+
+    fn measures_replies(&self) -> bool {
+        true
+    }
+}
+
+impl Strategy for PeakEwmaStrategy {
+    fn select(&mut self, context: &Context, pick: &mut Pick) -> Selection {
+        self.select_available(context, pick, &|_| true)
+    }
+
+    fn select_available(
+        &mut self,
+        context: &Context,
+        _pick: &mut Pick,
+        available: &dyn Fn(&Endpoint) -> bool,
+    ) -> Selection {
+        let now = Instant::now();
+        let count = self.peers.len();
+        let cost = |index: usize| self.estimate(index, now) * (self.in_flight[index] + 1) as f64;
+        let best = (0..count)
+            .map(|offset| (self.next + offset) % count)
+            .filter(|&index| available(&self.peers[index]))
+            .min_by(|&a, &b| cost(a).total_cmp(&cost(b)));
+        let best = match best {
+            Some(best) => best,
+            None => return Selection::Empty,
+        };
+        self.next = (best + 1) % count;
+        // Packets are never closed, so only connections are counted.
+        if context.protocol == Protocol::Tcp {
+            self.in_flight[best] += 1;
+        }
+        Selection::One(self.peers[best].clone())
+    }
+
+    fn outcome(&mut self, _pick: &Pick, destination: &Endpoint, outcome: Outcome) {
+        let index = match self.peers.iter().position(|peer| peer == destination) {
+            Some(index) => index,
+            None => return,
+        };
+        match outcome {
+            Outcome::Connected(latency) => self.observe(index, latency.as_secs_f64()),
+            Outcome::Replied(latency) => {
+                self.waiting[index] = None;
+                self.observe(index, latency.as_secs_f64());
+            }
+            Outcome::Failed => {
+                // A failure counts as at least the penalty, but never
+                // makes the destination look faster than before.
+                let current = self.estimate(index, Instant::now());
+                self.waiting[index] = None;
+                self.observe(index, current.max(self.penalty));
+            }
+            Outcome::Closed => {
+                self.in_flight[index] = self.in_flight[index].saturating_sub(1);
+            }
+            Outcome::Sent => {
+                self.waiting[index].get_or_insert_with(Instant::now);
+            }
+        }
+    }
+
+    fn measures_replies(&self) -> bool {
+        true
+    }
+}
+
+impl Strategy for StickyStrategy {
+    fn select(&mut self, context: &Context, pick: &mut Pick) -> Selection {
+        let now = Instant::now();
+        let key = self.client_key(context.client);
+        if let Some(affinity) = self.clients.get_mut(&key) {
+            if now.saturating_duration_since(affinity.used) < self.ttl {
+                affinity.used = now;
+                *pick = affinity.pick.clone();
+                pick.push(REMEMBERED);
+                return Selection::One(affinity.destination.clone());
+            }
+        }
+        let selection = self.base.select(context, pick);
+        // Only a single destination can be remembered, so broadcasts
+        // are never sticky.
+        if let Selection::One(destination) = &selection {
+            self.remember(key, destination.clone(), pick.clone(), now);
+        }
+        pick.push(BASE);
+        selection
+    }
+
+    fn outcome(&mut self, pick: &Pick, destination: &Endpoint, outcome: Outcome) {
+        if outcome == Outcome::Failed {
+            self.clients
+                .retain(|_, affinity| affinity.destination != *destination);
+        }
+        let (index, inner) = pick.split().unwrap_or_default();
+        // Connections to remembered destinations were not counted by
+        // the base strategy, so their closing is not reported to it.
+        if index == REMEMBERED && outcome == Outcome::Closed {
+            return;
+        }
+        self.base.outcome(&inner, destination, outcome);
+    }
+
+    fn measures_replies(&self) -> bool {
+        self.base.measures_replies()
+    }
+}
+
+impl Strategy for SourceRoutingStrategy {
+    fn select(&mut self, context: &Context, pick: &mut Pick) -> Selection {
+        let client = context.client;
+        let index = self
+            .routes
+            .iter()
+            .position(|route| route.matches(client))
+            .unwrap_or(self.routes.len());
+        let selection = self.strategies[index].select(context, pick);
+        pick.push(index);
+        selection
+    }
+
+    fn outcome(&mut self, pick: &Pick, destination: &Endpoint, outcome: Outcome) {
+        if let Some((index, inner)) = pick.split() {
+            if let Some(strategy) = self.strategies.get_mut(index) {
+                strategy.outcome(&inner, destination, outcome);
+            }
+        }
+    }
+
+    fn measures_replies(&self) -> bool {
+        self.strategies
+            .iter()
+            .any(|strategy| strategy.measures_replies())
+    }
+}
+
+impl Strategy for TieredStrategy {
+    fn select(&mut self, context: &Context, pick: &mut Pick) -> Selection {
+        let failed = &self.failed;
+        let retry = self.retry;
+        let is_up = |peer: &Endpoint| failed.get(peer).is_none_or(|when| when.elapsed() >= retry);
+        for (index, tier) in self.tiers.iter_mut().enumerate() {
+            if !tier.peers.iter().any(is_up) {
+                continue;
+            }
+            // Skip past destinations that are down, which takes at
+            // most one round for the strategies that rotate. Clients
+            // that hash to a destination that is down move on to the
+            // next tier.
+            for _ in 0..tier.peers.len() {
+                let mut inner = Pick::default();
+                let selection = tier.strategy.select_available(context, &mut inner, &is_up);
+                let selection = selection.filter(is_up);
+                if selection != Selection::Empty {
+                    *pick = inner;
+                    pick.push(index);
+                    return selection;
+                }
+            }
+        }
+        let selection = self.tiers[0].strategy.select(context, pick);
+        pick.push(0);
+        selection
+    }
+
+    fn outcome(&mut self, pick: &Pick, destination: &Endpoint, outcome: Outcome) {
+        match outcome {
+            Outcome::Connected(_) | Outcome::Replied(_) => {
+                if self.failed.remove(destination).is_some() {
+                    info!("destination {} is up again", destination);
+                }
+            }
+            Outcome::Failed => {
+                info!("destination {} is down", destination);
+                self.failed.insert(destination.clone(), Instant::now());
+            }
+            Outcome::Sent | Outcome::Closed => {}
+        }
+        if let Some((index, inner)) = pick.split() {
+            if let Some(tier) = self.tiers.get_mut(index) {
+                tier.strategy.outcome(&inner, destination, outcome);
+            }
+        }
+    }
+
+    fn measures_replies(&self) -> bool {
+        self.tiers
+            .iter()
+            .any(|tier| tier.strategy.measures_replies())
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -95,6 +1255,13 @@ impl std::fmt::Display for Mode {
         match self {
             Mode::RoundRobin => write!(f, "RoundRobin"),
             Mode::Broadcast => write!(f, "Broadcast"),
+            Mode::WeightedRoundRobin => write!(f, "WeightedRoundRobin"),
+            Mode::LeastConnections => write!(f, "LeastConnections"),
+            Mode::ConsistentHash => write!(f, "ConsistentHash"),
+            Mode::Random => write!(f, "Random"),
+            Mode::PowerOfTwoChoices => write!(f, "PowerOfTwoChoices"),
+            Mode::PeakEwma => write!(f, "PeakEwma"),
+            Mode::Script => write!(f, "Script"),
         }
     }
 }
@@ -107,6 +1274,20 @@ impl FromStr for Mode {
             Ok(Mode::RoundRobin)
         } else if s.eq_ignore_ascii_case("broadcast") {
             Ok(Mode::Broadcast)
+        } else if s.eq_ignore_ascii_case("weightedroundrobin") {
+            Ok(Mode::WeightedRoundRobin)
+        } else if s.eq_ignore_ascii_case("leastconnections") {
+            Ok(Mode::LeastConnections)
+        } else if s.eq_ignore_ascii_case("consistenthash") {
+            Ok(Mode::ConsistentHash)
+        } else if s.eq_ignore_ascii_case("random") {
+            Ok(Mode::Random)
+        } else if s.eq_ignore_ascii_case("p2c") || s.eq_ignore_ascii_case("poweroftwochoices") {
+            Ok(Mode::PowerOfTwoChoices)
+        } else if s.eq_ignore_ascii_case("peakewma") {
+            Ok(Mode::PeakEwma)
+        } else if s.eq_ignore_ascii_case("script") {
+            Ok(Mode::Script)
         } else {
             Err(Error::ParseModeError(s.into()))
         }
@@ -116,10 +1297,711 @@ impl FromStr for Mode {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::session::rules::Script;
+    use serde_json::json;
+
+    fn client() -> Endpoint {
+        "127.0.0.1:5000".parse().unwrap()
+    }
+
+    /// Destinations on consecutive ports, starting at 9001.
+    fn peers(count: u16) -> Vec<Endpoint> {
+        (1..=count)
+            .map(|n| format!("127.0.0.1:{}", 9000 + n).parse().unwrap())
+            .collect()
+    }
+
+    fn context(client: &Endpoint) -> Context<'_> {
+        Context {
+            rule_id: 0,
+            protocol: Protocol::Tcp,
+            client,
+            peeked: None,
+        }
+    }
+
+    /// Select a single destination for a client.
+    fn pick(strategy: &mut dyn Strategy, client: &Endpoint) -> Endpoint {
+        picked(strategy, client).0
+    }
+
+    /// Select a single destination for a client, together with the
+    /// pick to report its outcomes with.
+    fn picked(strategy: &mut dyn Strategy, client: &Endpoint) -> (Endpoint, Pick) {
+        let mut pick = Pick::default();
+        match strategy.select(&context(client), &mut pick) {
+            Selection::One(destination) => (destination, pick),
+            selection => panic!("expected one destination, got {:?}", selection),
+        }
+    }
 
     #[test]
     fn test_mode() {
         assert_eq!("roundrobin".parse(), Ok(Mode::RoundRobin));
         assert_eq!("broadcast".parse(), Ok(Mode::Broadcast));
+        assert_eq!("weightedroundrobin".parse(), Ok(Mode::WeightedRoundRobin));
+    }
+
+    /// Strategy always selecting the same destination.
+    struct Fixed(Endpoint);
+
+    impl Strategy for Fixed {
+        fn select(&mut self, _context: &Context, _pick: &mut Pick) -> Selection {
+            Selection::One(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        StrategyRegistry::register("test-fixed", |destinations, params| {
+            let index = params["index"].as_u64().ok_or("no index")? as usize;
+            let destination = destinations.get(index).ok_or("index out of range")?;
+            Ok(Box::new(Fixed(destination.clone())))
+        });
+        assert!(StrategyRegistry::names().contains(&"test-fixed".to_string()));
+
+        let client = client();
+        let peers = peers(2);
+        let named = |name: &str, params| NamedStrategy {
+            name: name.to_string(),
+            params,
+        };
+        let mut strategy =
+            StrategyRegistry::build(&named("test-fixed", json!({"index": 1})), &peers).unwrap();
+        assert_eq!(pick(strategy.as_mut(), &client), peers[1]);
+        let params = json!({"index": 2});
+        assert!(StrategyRegistry::build(&named("test-fixed", params), &peers).is_err());
+        assert!(StrategyRegistry::build(&named("test-missing", json!(null)), &peers).is_err());
+    }
+
+    #[test]
+    fn test_selection() {
+        let client = client();
+        let peers = peers(2);
+        let mut broadcast = BroadcastStrategy::new(&peers);
+        let selection = broadcast.select(&context(&client), &mut Pick::default());
+        assert_eq!(selection.destinations(), &peers[..]);
+        let selection = selection.filter(|peer| *peer == peers[1]);
+        assert_eq!(selection, Selection::All(peers[1..].to_vec()));
+        assert_eq!(selection.filter(|_| false), Selection::Empty);
+
+        let mut round_robin = RoundRobinStrategy::new(&peers);
+        assert_eq!(pick(&mut round_robin, &client), peers[0]);
+        assert_eq!(pick(&mut round_robin, &client), peers[1]);
+        assert_eq!(pick(&mut round_robin, &client), peers[0]);
+    }
+
+    #[test]
+    fn test_empty_pool() {
+        let client = client();
+        let path = std::env::temp_dir().join(format!("router-empty-{}.rhai", std::process::id()));
+        std::fs::write(&path, "destinations[0]").unwrap();
+        let modes = [
+            Mode::RoundRobin,
+            Mode::Broadcast,
+            Mode::WeightedRoundRobin,
+            Mode::LeastConnections,
+            Mode::ConsistentHash,
+            Mode::Random,
+            Mode::PowerOfTwoChoices,
+            Mode::PeakEwma,
+            Mode::Script,
+        ];
+        for &mode in &modes {
+            let source = "127.0.0.1:9000".parse().unwrap();
+            let mut rule = Rule::new(Protocol::Tcp, mode, source, Vec::new());
+            if mode == Mode::Script {
+                rule.script = Some(Script {
+                    path: path.clone(),
+                    max_operations: None,
+                });
+            }
+            let mut strategy = StrategyFactory::make(&rule, &Default::default());
+            let selection = strategy.select(&context(&client), &mut Pick::default());
+            assert!(
+                selection.destinations().is_empty(),
+                "{} selected {:?}",
+                mode,
+                selection
+            );
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_weighted() {
+        let client = client();
+        let peers = peers(3);
+        let weights = Arc::new(Weights::new(&[(peers[0].clone(), 5)].into()));
+        let mut strategy = WeightedRoundRobinStrategy::new(&peers, weights.clone());
+        let picks: Vec<usize> = (0..7)
+            .map(|_| {
+                let picked = pick(&mut strategy, &client);
+                peers.iter().position(|peer| *peer == picked).unwrap()
+            })
+            .collect();
+        assert_eq!(picks, vec![0, 0, 1, 0, 2, 0, 0]);
+
+        // Changing the weights keeps the strategy going.
+        weights.set(&[(peers[0].clone(), 0), (peers[1].clone(), 2)].into());
+        let mut counts = [0; 3];
+        for _ in 0..30 {
+            let picked = pick(&mut strategy, &client);
+            counts[peers.iter().position(|peer| *peer == picked).unwrap()] += 1;
+        }
+        assert_eq!(counts, [0, 20, 10]);
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let client = client();
+        let peers = peers(3);
+        let mut strategy = LeastConnectionsStrategy::new(&peers);
+
+        // Equally loaded destinations take turns.
+        assert_eq!(pick(&mut strategy, &client), peers[0]);
+        assert_eq!(pick(&mut strategy, &client), peers[1]);
+        assert_eq!(pick(&mut strategy, &client), peers[2]);
+        assert_eq!(pick(&mut strategy, &client), peers[0]);
+
+        // The destination with the fewest connections is picked.
+        strategy.outcome(&Pick::default(), &peers[1], Outcome::Closed);
+        assert_eq!(pick(&mut strategy, &client), peers[1]);
+        strategy.outcome(&Pick::default(), &peers[2], Outcome::Closed);
+        strategy.outcome(&Pick::default(), &peers[0], Outcome::Closed);
+        strategy.outcome(&Pick::default(), &peers[0], Outcome::Closed);
+        assert_eq!(pick(&mut strategy, &client), peers[2]);
+        assert_eq!(pick(&mut strategy, &client), peers[0]);
+        assert_eq!(pick(&mut strategy, &client), peers[1]);
+    }
+
+    #[test]
+    fn test_random() {
+        let client = client();
+        let peers = peers(4);
+        let picks = |seed| {
+            let mut strategy = RandomStrategy::new(&peers, Some(seed));
+            (0..100)
+                .map(|_| pick(&mut strategy, &client))
+                .collect::<Vec<_>>()
+        };
+        let first = picks(17);
+        assert_eq!(first, picks(17));
+        assert_ne!(first, picks(18));
+        assert!(peers.iter().all(|peer| first.contains(peer)));
+
+        // Only available destinations are drawn.
+        let mut strategy = RandomStrategy::new(&peers, Some(17));
+        let context = context(&client);
+        let mut select = |available: &dyn Fn(&Endpoint) -> bool| {
+            strategy.select_available(&context, &mut Pick::default(), available)
+        };
+        for _ in 0..20 {
+            let selection = select(&|peer| *peer == peers[2]);
+            assert_eq!(selection, Selection::One(peers[2].clone()));
+        }
+        assert_eq!(select(&|_| false), Selection::Empty);
+    }
+
+    #[test]
+    fn test_power_of_two_choices() {
+        let client = client();
+        let peers = peers(2);
+
+        // With two destinations both are always sampled, so the one
+        // with fewer connections in flight is picked.
+        let mut strategy = PowerOfTwoChoicesStrategy::new(&peers, Some(1));
+        let first = pick(&mut strategy, &client);
+        let second = pick(&mut strategy, &client);
+        assert_ne!(first, second);
+        strategy.outcome(&Pick::default(), &first, Outcome::Closed);
+        assert_eq!(pick(&mut strategy, &client), first);
+
+        // Equally loaded destinations are told apart by connect time.
+        let mut strategy = PowerOfTwoChoicesStrategy::new(&peers, Some(1));
+        strategy.outcome(
+            &Pick::default(),
+            &peers[0],
+            Outcome::Connected(Duration::from_millis(30)),
+        );
+        strategy.outcome(
+            &Pick::default(),
+            &peers[1],
+            Outcome::Connected(Duration::from_millis(5)),
+        );
+        for _ in 0..10 {
+            let picked = pick(&mut strategy, &client);
+            assert_eq!(picked, peers[1]);
+            strategy.outcome(&Pick::default(), &picked, Outcome::Closed);
+        }
+    }
+
+    #[test]
+    fn test_peak_ewma() {
+        let client = client();
+        let peers = peers(2);
+        let latencies = Arc::new(Latencies::default());
+        let mut strategy = PeakEwmaStrategy::new(&peers, &PeakEwma::default(), latencies.clone());
+
+        // The faster destination is preferred until it has enough
+        // connections in flight.
+        strategy.outcome(
+            &Pick::default(),
+            &peers[0],
+            Outcome::Connected(Duration::from_millis(30)),
+        );
+        strategy.outcome(
+            &Pick::default(),
+            &peers[1],
+            Outcome::Connected(Duration::from_millis(5)),
+        );
+        for _ in 0..5 {
+            assert_eq!(pick(&mut strategy, &client), peers[1]);
+        }
+        assert_eq!(pick(&mut strategy, &client), peers[0]);
+
+        // A slower connect time is used at once and is reported.
+        strategy.outcome(
+            &Pick::default(),
+            &peers[1],
+            Outcome::Connected(Duration::from_millis(50)),
+        );
+        let latency = latencies.get()[&peers[1]];
+        assert!(latency >= Duration::from_millis(49) && latency <= Duration::from_millis(51));
+        assert_eq!(pick(&mut strategy, &client), peers[0]);
+
+        // Without new connections, the latency decays.
+        let earlier = Instant::now() - Duration::from_secs(60);
+        strategy.latency[1] = Some((0.05, earlier));
+        assert!(strategy.estimate(1, Instant::now()) < 0.001);
+
+        // Destinations without a connect time get the penalty.
+        let settings = PeakEwma {
+            decay_ms: None,
+            penalty_ms: Some(1),
+        };
+        let mut strategy = PeakEwmaStrategy::new(&peers, &settings, latencies);
+        strategy.outcome(
+            &Pick::default(),
+            &peers[0],
+            Outcome::Connected(Duration::from_millis(5)),
+        );
+        assert_eq!(pick(&mut strategy, &client), peers[1]);
+    }
+
+    #[test]
+    fn test_peak_ewma_udp() {
+        let client = client();
+        let peers = peers(2);
+        let latencies = Arc::new(Latencies::default());
+        let mut strategy = PeakEwmaStrategy::new(&peers, &PeakEwma::default(), latencies.clone());
+        let context = Context {
+            protocol: Protocol::Udp,
+            ..context(&client)
+        };
+
+        // Response times are used as latencies and packets are not
+        // counted as in flight.
+        strategy.outcome(&Pick::default(), &peers[0], Outcome::Sent);
+        strategy.outcome(
+            &Pick::default(),
+            &peers[0],
+            Outcome::Replied(Duration::from_millis(30)),
+        );
+        strategy.outcome(&Pick::default(), &peers[1], Outcome::Sent);
+        strategy.outcome(
+            &Pick::default(),
+            &peers[1],
+            Outcome::Replied(Duration::from_millis(5)),
+        );
+        for _ in 0..10 {
+            assert_eq!(
+                strategy.select(&context, &mut Pick::default()),
+                Selection::One(peers[1].clone())
+            );
+        }
+        let latency = latencies.get()[&peers[1]];
+        assert!(latency >= Duration::from_millis(4) && latency <= Duration::from_millis(6));
+
+        // A destination that has not replied for a while is at least
+        // as slow as the time spent waiting.
+        strategy.outcome(&Pick::default(), &peers[1], Outcome::Sent);
+        strategy.waiting[1] = Some(Instant::now() - Duration::from_secs(1));
+        assert_eq!(
+            strategy.select(&context, &mut Pick::default()),
+            Selection::One(peers[0].clone())
+        );
+        strategy.outcome(
+            &Pick::default(),
+            &peers[1],
+            Outcome::Replied(Duration::from_millis(5)),
+        );
+        assert_eq!(strategy.waiting[1], None);
+
+        // A packet that is never replied to stops the wait, and the
+        // destination stays as slow as the time spent waiting.
+        strategy.outcome(&Pick::default(), &peers[0], Outcome::Sent);
+        strategy.waiting[0] = Some(Instant::now() - Duration::from_secs(1));
+        strategy.outcome(&Pick::default(), &peers[0], Outcome::Failed);
+        assert_eq!(strategy.waiting[0], None);
+        assert!(strategy.estimate(0, Instant::now()) >= 0.999);
+        assert!(strategy.measures_replies());
+        assert!(!RoundRobinStrategy::new(&peers).measures_replies());
+    }
+
+    #[test]
+    fn test_sticky() {
+        let peers = peers(3);
+        let clients: Vec<Endpoint> = (5001..=5003)
+            .map(|port| format!("10.0.0.{}:{}", port - 5000, port).parse().unwrap())
+            .collect();
+        let settings = Sticky {
+            ttl_ms: Some(1000),
+            max_clients: Some(2),
+            ..Default::default()
+        };
+        let base = Box::new(RoundRobinStrategy::new(&peers));
+        let mut strategy = StickyStrategy::new(base, &settings);
+
+        // Clients keep their destination, also from another port.
+        assert_eq!(pick(&mut strategy, &clients[0]), peers[0]);
+        assert_eq!(pick(&mut strategy, &clients[1]), peers[1]);
+        let other_port = "10.0.0.1:6000".parse().unwrap();
+        assert_eq!(pick(&mut strategy, &other_port), peers[0]);
+        assert_eq!(pick(&mut strategy, &clients[1]), peers[1]);
+
+        // A failed destination makes its clients pick again.
+        strategy.outcome(&Pick::default(), &peers[0], Outcome::Failed);
+        assert_eq!(pick(&mut strategy, &clients[0]), peers[2]);
+        assert_eq!(pick(&mut strategy, &clients[0]), peers[2]);
+
+        // The least recently seen client is forgotten when the table
+        // is full.
+        assert_eq!(pick(&mut strategy, &clients[2]), peers[0]);
+        assert_eq!(strategy.clients.len(), 2);
+        assert_eq!(pick(&mut strategy, &clients[1]), peers[1]);
+
+        // Idle clients pick again.
+        let key = strategy.client_key(&clients[1]);
+        strategy.clients.get_mut(&key).unwrap().used -= Duration::from_secs(1);
+        assert_eq!(pick(&mut strategy, &clients[1]), peers[2]);
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let peers = peers(5);
+        let clients: Vec<Endpoint> = (0..1000)
+            .map(|n| {
+                format!("10.0.{}.{}:{}", n / 250, n % 250, 40000 + n)
+                    .parse()
+                    .unwrap()
+            })
+            .collect();
+        let settings = ConsistentHash::default();
+        let pick_all = |peers: &[Endpoint]| -> Vec<Endpoint> {
+            let mut strategy = ConsistentHashStrategy::new(peers, &settings);
+            clients
+                .iter()
+                .map(|client| pick(&mut strategy, client))
+                .collect()
+        };
+        let before = pick_all(&peers);
+        for peer in &peers {
+            let share = before.iter().filter(|picked| *picked == peer).count();
+            assert!((100..=300).contains(&share), "{} got {}", peer, share);
+        }
+
+        // Removing a destination only moves the clients it had.
+        let after = pick_all(&peers[..4]);
+        for (old, new) in before.iter().zip(&after) {
+            if *old != peers[4] {
+                assert_eq!(old, new);
+            }
+        }
+
+        // Clients are keyed by IP address by default, and by the full
+        // address if requested.
+        let settings = ConsistentHash {
+            key: HashKey::Address,
+            ..Default::default()
+        };
+        let mut by_ip = ConsistentHashStrategy::new(&peers, &ConsistentHash::default());
+        let mut by_address = ConsistentHashStrategy::new(&peers, &settings);
+        let ports: Vec<Endpoint> = (40000..40100)
+            .map(|port| format!("10.1.1.1:{}", port).parse().unwrap())
+            .collect();
+        let mut picked: Vec<Endpoint> = ports
+            .iter()
+            .map(|client| pick(&mut by_ip, client))
+            .collect();
+        picked.dedup();
+        assert_eq!(picked.len(), 1);
+        let mut picked: Vec<Endpoint> = ports
+            .iter()
+            .map(|client| pick(&mut by_address, client))
+            .collect();
+        picked.sort();
+        picked.dedup();
+        assert!(picked.len() > 1);
+    }
+
+    #[test]
+    fn test_tiers() {
+        let client = client();
+        let endpoints = peers(4);
+        let mut rule = Rule::new(
+            Protocol::Tcp,
+            Mode::RoundRobin,
+            "127.0.0.1:9000".parse().unwrap(),
+            endpoints[..2].to_vec(),
+        );
+        let backups = Backups {
+            tiers: vec![vec![endpoints[2].clone()], vec![endpoints[3].clone()]],
+            retry_ms: Some(50),
+        };
+        rule.backups = Some(backups.clone());
+        let mut strategy = TieredStrategy::new(&rule, &backups, &Default::default());
+        assert_eq!(pick(&mut strategy, &client), endpoints[0]);
+        assert_eq!(pick(&mut strategy, &client), endpoints[1]);
+
+        // One primary left, then the first backup tier.
+        strategy.outcome(&Pick::default(), &endpoints[0], Outcome::Failed);
+        assert_eq!(pick(&mut strategy, &client), endpoints[1]);
+        assert_eq!(pick(&mut strategy, &client), endpoints[1]);
+        strategy.outcome(&Pick::default(), &endpoints[1], Outcome::Failed);
+        assert_eq!(pick(&mut strategy, &client), endpoints[2]);
+        strategy.outcome(&Pick::default(), &endpoints[2], Outcome::Failed);
+        let (backup, backup_pick) = picked(&mut strategy, &client);
+        assert_eq!(backup, endpoints[3]);
+
+        // The outcomes of a pick are only reported to its tier.
+        assert_eq!(backup_pick.split().map(|(tier, _)| tier), Some(2));
+
+        // A primary that works again is used immediately.
+        strategy.outcome(
+            &Pick::default(),
+            &endpoints[1],
+            Outcome::Connected(Duration::from_millis(1)),
+        );
+        assert_eq!(pick(&mut strategy, &client), endpoints[1]);
+
+        // Failed destinations are retried after a while.
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(pick(&mut strategy, &client), endpoints[0]);
+        assert_eq!(pick(&mut strategy, &client), endpoints[1]);
+    }
+
+    #[test]
+    fn test_tiers_least_connections() {
+        let client = client();
+        let endpoints = peers(3);
+        let mut rule = Rule::new(
+            Protocol::Tcp,
+            Mode::LeastConnections,
+            "127.0.0.1:9000".parse().unwrap(),
+            endpoints[..2].to_vec(),
+        );
+        let backups = Backups {
+            tiers: vec![vec![endpoints[2].clone()]],
+            retry_ms: Some(50),
+        };
+        rule.backups = Some(backups.clone());
+        let mut strategy = TieredStrategy::new(&rule, &backups, &Default::default());
+        let (first, first_pick) = picked(&mut strategy, &client);
+        assert_eq!(first, endpoints[0]);
+        strategy.outcome(&first_pick, &first, Outcome::Failed);
+        strategy.outcome(&first_pick, &first, Outcome::Closed);
+
+        // The failed destination is not picked, and not counted, while
+        // the other primary has more connections.
+        let mut open = Vec::new();
+        for _ in 0..3 {
+            let (destination, pick) = picked(&mut strategy, &client);
+            assert_eq!(destination, endpoints[1]);
+            open.push(pick);
+        }
+
+        // With the primaries down, the backup is used.
+        strategy.outcome(&open[0], &endpoints[1], Outcome::Failed);
+        let (backup, backup_pick) = picked(&mut strategy, &client);
+        assert_eq!(backup, endpoints[2]);
+        strategy.outcome(&backup_pick, &backup, Outcome::Closed);
+        for pick in &open {
+            strategy.outcome(pick, &endpoints[1], Outcome::Closed);
+        }
+
+        // After the recovery, both primaries are equally loaded and
+        // take turns.
+        std::thread::sleep(Duration::from_millis(60));
+        let mut picked: Vec<Endpoint> = (0..4).map(|_| pick(&mut strategy, &client)).collect();
+        picked.sort();
+        assert_eq!(
+            picked,
+            vec![
+                endpoints[0].clone(),
+                endpoints[0].clone(),
+                endpoints[1].clone(),
+                endpoints[1].clone()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_source_routes() {
+        let endpoints = peers(5);
+        let mut rule = Rule::new(
+            Protocol::Udp,
+            Mode::RoundRobin,
+            "127.0.0.1:9000".parse().unwrap(),
+            endpoints[..1].to_vec(),
+        );
+        rule.source_routes = vec![
+            SourceRoute {
+                network: "10.1.0.0/16".parse().unwrap(),
+                port_range: Some(PortRange {
+                    first: 1000,
+                    last: 1999,
+                }),
+                destinations: endpoints[1..2].to_vec(),
+                mode: None,
+            },
+            SourceRoute {
+                network: "10.0.0.0/8".parse().unwrap(),
+                port_range: None,
+                destinations: endpoints[2..4].to_vec(),
+                mode: Some(Mode::Broadcast),
+            },
+            SourceRoute {
+                network: "::/0".parse().unwrap(),
+                port_range: None,
+                destinations: endpoints[4..].to_vec(),
+                mode: None,
+            },
+        ];
+        let mut strategy = StrategyFactory::make(&rule, &Default::default());
+        let select = |strategy: &mut dyn Strategy, client: &str| {
+            let client: Endpoint = client.parse().unwrap();
+            strategy
+                .select(&context(&client), &mut Pick::default())
+                .destinations()
+                .to_vec()
+        };
+        assert_eq!(select(&mut *strategy, "10.1.2.3:1500"), &endpoints[1..2]);
+        assert_eq!(select(&mut *strategy, "10.1.2.3:2500"), &endpoints[2..4]);
+        assert_eq!(select(&mut *strategy, "10.2.2.3:1500"), &endpoints[2..4]);
+        assert_eq!(select(&mut *strategy, "[::1]:1500"), &endpoints[4..]);
+        assert_eq!(select(&mut *strategy, "192.168.1.1:1500"), &endpoints[..1]);
+        assert_eq!(select(&mut *strategy, "unix:/tmp/client"), &endpoints[..1]);
+    }
+
+    #[test]
+    fn test_source_routes_outcomes() {
+        let endpoints = peers(2);
+        let mut rule = Rule::new(
+            Protocol::Tcp,
+            Mode::LeastConnections,
+            "127.0.0.1:9000".parse().unwrap(),
+            endpoints.clone(),
+        );
+        rule.source_routes = vec![SourceRoute {
+            network: "10.0.0.0/8".parse().unwrap(),
+            port_range: None,
+            destinations: endpoints[..1].to_vec(),
+            mode: None,
+        }];
+        let mut strategy = StrategyFactory::make(&rule, &Default::default());
+        let inside: Endpoint = "10.0.0.1:5000".parse().unwrap();
+        let outside: Endpoint = "192.168.0.1:5000".parse().unwrap();
+
+        // The default has one connection to the first destination.
+        assert_eq!(pick(&mut *strategy, &outside), endpoints[0]);
+        let (second, second_pick) = picked(&mut *strategy, &outside);
+        assert_eq!(second, endpoints[1]);
+        strategy.outcome(&second_pick, &second, Outcome::Closed);
+
+        // Closing a connection picked by the route does not close the
+        // connection of the default.
+        let (routed, routed_pick) = picked(&mut *strategy, &inside);
+        assert_eq!(routed, endpoints[0]);
+        strategy.outcome(&routed_pick, &routed, Outcome::Closed);
+        assert_eq!(pick(&mut *strategy, &outside), endpoints[1]);
+    }
+
+    #[test]
+    fn test_source_routes_close_order() {
+        let endpoints = peers(2);
+        let mut rule = Rule::new(
+            Protocol::Tcp,
+            Mode::LeastConnections,
+            "127.0.0.1:9000".parse().unwrap(),
+            endpoints.clone(),
+        );
+        rule.source_routes = vec![SourceRoute {
+            network: "10.0.0.0/8".parse().unwrap(),
+            port_range: None,
+            destinations: endpoints[..1].to_vec(),
+            mode: None,
+        }];
+        let mut strategy = StrategyFactory::make(&rule, &Default::default());
+        let inside: Endpoint = "10.0.0.1:5000".parse().unwrap();
+        let outside: Endpoint = "192.168.0.1:5000".parse().unwrap();
+
+        // The route opens a connection to the first destination before
+        // the default opens two.
+        assert_eq!(pick(&mut *strategy, &inside), endpoints[0]);
+        let opened: Vec<(Endpoint, Pick)> =
+            (0..3).map(|_| picked(&mut *strategy, &outside)).collect();
+        assert_eq!(opened[0].0, endpoints[0]);
+        assert_eq!(opened[1].0, endpoints[1]);
+        assert_eq!(opened[2].0, endpoints[0]);
+
+        // Closing them before the connection of the route leaves the
+        // default without connections to the first destination.
+        for (destination, pick) in [&opened[2], &opened[0]] {
+            strategy.outcome(pick, destination, Outcome::Closed);
+        }
+        assert_eq!(pick(&mut *strategy, &outside), endpoints[0]);
+    }
+
+    #[test]
+    fn test_source_routes_replies() {
+        let endpoints = peers(2);
+        let mut rule = Rule::new(
+            Protocol::Udp,
+            Mode::PeakEwma,
+            "127.0.0.1:9000".parse().unwrap(),
+            endpoints.clone(),
+        );
+        rule.source_routes = vec![SourceRoute {
+            network: "10.0.0.0/8".parse().unwrap(),
+            port_range: None,
+            destinations: endpoints.clone(),
+            mode: None,
+        }];
+        let mut strategy = StrategyFactory::make(&rule, &Default::default());
+        let inside: Endpoint = "10.0.0.1:5000".parse().unwrap();
+        let outside: Endpoint = "192.168.0.1:5000".parse().unwrap();
+        let send = |strategy: &mut dyn Strategy, client: &Endpoint| {
+            let context = Context {
+                protocol: Protocol::Udp,
+                ..context(client)
+            };
+            let mut pick = Pick::default();
+            let selection = strategy.select(&context, &mut pick);
+            let destination = selection.destinations()[0].clone();
+            strategy.outcome(&pick, &destination, Outcome::Sent);
+            (destination, pick)
+        };
+
+        // A slow reply to a packet of the route is reported to the
+        // route, even after the default has sent a packet.
+        let (routed, routed_pick) = send(&mut *strategy, &inside);
+        assert_eq!(send(&mut *strategy, &outside).0, routed);
+        let replied = Outcome::Replied(Duration::from_millis(500));
+        strategy.outcome(&routed_pick, &routed, replied);
+        for _ in 0..10 {
+            let (destination, _) = send(&mut *strategy, &inside);
+            assert_ne!(destination, routed);
+        }
     }
 }
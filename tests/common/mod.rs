@@ -12,9 +12,11 @@ use std::{
     env, error,
     fmt::{self, Display},
     io::{self, BufRead, BufReader},
-    net::UdpSocket,
+    net::{TcpStream, UdpSocket},
     process::{Child, Command, Stdio},
     str::from_utf8,
+    thread,
+    time::{Duration, Instant},
 };
 use tokio::runtime::Runtime;
 
@@ -26,7 +28,7 @@ use tokio::runtime::Runtime;
 ///
 /// # Example
 ///
-/// ```
+/// ```ignore
 /// const CONFIG: &str = r#"{
 ///   "protocol": "udp",
 ///   "mode": "broadcast",
@@ -75,9 +77,14 @@ impl Harness {
     }
 
     pub fn start(&mut self) -> Result<(), Error> {
-        // Set up listeners on destinations.
-        let receivers: Result<Vec<_>, _> =
-            self.rule.destinations.iter().map(UdpSocket::bind).collect();
+        // Set up listeners on destinations, which are all IP
+        // addresses in the tests.
+        let receivers: Result<Vec<_>, _> = self
+            .rule
+            .destinations
+            .iter()
+            .map(|destination| UdpSocket::bind(destination.inet().unwrap()))
+            .collect();
 
         let receivers = match receivers {
             Ok(recv) => recv,
@@ -103,14 +110,18 @@ impl Harness {
                 .expect("unable to start network router"),
         )?;
 
+        // The sessions can start before the web interface, so wait
+        // for it to accept connections.
+        wait_until_listening(&self.connection.endpoint)?;
+
         // Open a sender socket
         let sender = UdpSocket::bind("0.0.0.0:0")?;
 
         self.runtime = Some(Runtime::new()?);
         self.state = Some(State {
             child,
-            sender: sender,
-            receivers: receivers,
+            sender,
+            receivers,
         });
         Ok(())
     }
@@ -123,7 +134,9 @@ impl Harness {
         match self.state {
             Some(ref state) => match self.rule.mode {
                 Mode::Broadcast => {
-                    state.sender.send_to(packet.as_bytes(), self.rule.source)?;
+                    state
+                        .sender
+                        .send_to(packet.as_bytes(), self.rule.source.inet().unwrap())?;
                     for receiver in &state.receivers {
                         let mut buf = [0; 1500];
                         let bytes = receiver.recv(&mut buf)?;
@@ -131,11 +144,18 @@ impl Harness {
                     }
                     Ok(())
                 }
-                Mode::RoundRobin => {
+                Mode::RoundRobin
+                | Mode::WeightedRoundRobin
+                | Mode::LeastConnections
+                | Mode::ConsistentHash
+                | Mode::Random
+                | Mode::PowerOfTwoChoices
+                | Mode::PeakEwma
+                | Mode::Script => {
                     todo!();
                 }
             },
-            None => Err(Error(format!("not started"))),
+            None => Err(Error("not started".to_string())),
         }
     }
 
@@ -262,3 +282,20 @@ fn wait_until_started(mut child: Child) -> Result<Child, Error> {
     }
     Ok(child)
 }
+
+/// Wait for the web interface to accept connections.
+fn wait_until_listening(endpoint: &Web) -> Result<(), Error> {
+    let addr = match endpoint {
+        Web::Port(Some(port)) => format!("127.0.0.1:{}", port),
+        Web::Address(addr) => addr.to_string(),
+        Web::Port(None) => return Ok(()),
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(&addr).is_err() {
+        if Instant::now() > deadline {
+            return Err(Error(format!("web interface not listening on {}", addr)));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
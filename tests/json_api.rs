@@ -3,7 +3,7 @@ mod common;
 use crate::common::Harness;
 use bytes::Buf;
 use hyper::{Body, Method, StatusCode};
-use router::session::Rule;
+use router::session::{RateLimit, RateLimits, Rule};
 use serde::Deserialize;
 
 const CONFIG: &str = r#"{
@@ -47,6 +47,21 @@ fn test_json() {
 
     // Check that deleting the rule actually deletes it.
     test_delete_rule(&mut harness, rule_no);
+
+    // Check that statistics are available for existing rules only.
+    test_rule_stats(&mut harness, rule_no);
+
+    // Check that rate limits can be changed for a running rule.
+    test_rate_limit(&mut harness);
+
+    // Check that connections can be listed and killed.
+    test_connections(&mut harness, rule_no);
+
+    // Check that weights can be changed for a running rule.
+    test_weights(&mut harness);
+
+    // Check that latencies are available for existing rules only.
+    test_latencies(&mut harness, rule_no);
 }
 
 fn test_add_rule(harness: &mut Harness, json: &'static str) -> usize {
@@ -95,6 +110,114 @@ fn test_update_rule(harness: &mut Harness, rule_no: usize, json: &'static str) {
     );
 }
 
+fn test_rule_stats(harness: &mut Harness, deleted_rule_no: usize) {
+    let (body, status) = harness
+        .send_request(Method::GET, "/rules/0/stats", Body::default())
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    let stats: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+    assert!(stats.is_object());
+
+    let path = format!("/rules/{}/stats", deleted_rule_no);
+    let (_, status) = harness
+        .send_request(Method::GET, &path, Body::default())
+        .unwrap();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+fn test_rate_limit(harness: &mut Harness) {
+    let limits = RateLimits {
+        per_rule: Some(RateLimit {
+            bytes_per_second: 100_000,
+            burst: 1500,
+        }),
+        per_connection: None,
+    };
+    let body = Body::from(serde_json::to_string(&limits).unwrap());
+    let (_, status) = harness
+        .send_request(Method::PUT, "/rules/0/rate_limit", body)
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+
+    let (body, status) = harness
+        .send_request(Method::GET, "/rules/0/rate_limit", Body::default())
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    let actual: RateLimits = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(actual, limits);
+
+    // Connection limits are not supported for UDP rules.
+    let body = Body::from(r#"{"per_connection": {"bytes_per_second": 1000, "burst": 1500}}"#);
+    let (_, status) = harness
+        .send_request(Method::PUT, "/rules/0/rate_limit", body)
+        .unwrap();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let mut rule = Rule::from_json(CONFIG).unwrap();
+    rule.rate_limit = Some(limits);
+    expect_rules(harness, vec![rule]);
+}
+
+fn test_weights(harness: &mut Harness) {
+    let body = Body::from(r#"{"127.0.0.1:8081": 3, "127.0.0.1:8082": 1}"#);
+    let (_, status) = harness
+        .send_request(Method::PUT, "/rules/0/weights", body)
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+
+    let (body, status) = harness
+        .send_request(Method::GET, "/rules/0/weights", Body::default())
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    let weights: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(
+        weights,
+        serde_json::json!({"127.0.0.1:8081": 3, "127.0.0.1:8082": 1})
+    );
+
+    // Weights are only accepted for destinations of the rule.
+    let body = Body::from(r#"{"127.0.0.1:9999": 3}"#);
+    let (_, status) = harness
+        .send_request(Method::PUT, "/rules/0/weights", body)
+        .unwrap();
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+fn test_latencies(harness: &mut Harness, deleted_rule_no: usize) {
+    let (body, status) = harness
+        .send_request(Method::GET, "/rules/0/latencies", Body::default())
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    let latencies: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(latencies, serde_json::json!({}));
+
+    let path = format!("/rules/{}/latencies", deleted_rule_no);
+    let (_, status) = harness
+        .send_request(Method::GET, &path, Body::default())
+        .unwrap();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+fn test_connections(harness: &mut Harness, deleted_rule_no: usize) {
+    let (body, status) = harness
+        .send_request(Method::GET, "/rules/0/connections", Body::default())
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    let connections: serde_json::Value = serde_json::from_reader(body.reader()).unwrap();
+    assert_eq!(connections, serde_json::json!([]));
+
+    let (_, status) = harness
+        .send_request(Method::DELETE, "/rules/0/connections/17", Body::default())
+        .unwrap();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let path = format!("/rules/{}/connections", deleted_rule_no);
+    let (_, status) = harness
+        .send_request(Method::GET, &path, Body::default())
+        .unwrap();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
 fn expect_rules(harness: &mut Harness, expected_rules: Vec<Rule>) {
     let (body, status) = harness
         .send_request(Method::GET, "/rules", Body::default())
@@ -1,17 +1,9 @@
 use crate::common::Harness;
 use router::session::Rule;
-use std::{error::Error, process::Child};
+use std::error::Error;
 
 mod common;
 
-struct AutoKill(Child);
-
-impl Drop for AutoKill {
-    fn drop(&mut self) {
-        self.0.kill().expect("Could not kill child process");
-    }
-}
-
 const CONFIG: &str = r#"{
   "protocol": "udp",
   "mode": "broadcast",